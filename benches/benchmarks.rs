@@ -1,9 +1,23 @@
 use criterion::{black_box, criterion_group, criterion_main, Criterion};
 use tokio::runtime::Runtime;
-use turbo_zk_benchmark::udp_ping_pong::udp_ping_pong;
-use turbo_zk_benchmark::webrtc_benchmark::webrtc_benchmark;
-use turbo_zk_benchmark::websocket::websocket_benchmark;
-use turbo_zk_benchmark::zk_bellman::zk_bellman_benchmark;
+use std::time::Duration;
+use turbo_zk_benchmark::impairment::NetworkImpairment;
+use turbo_zk_benchmark::udp_ping_pong::{udp_ping_pong, UdpConfig};
+use turbo_zk_benchmark::webrtc_benchmark::{
+    webrtc_benchmark, webrtc_fanout_benchmark, IceGathering, WebRtcBenchMode, WebRtcConfig, WebRtcTopology,
+};
+use turbo_zk_benchmark::websocket::{websocket_benchmark, WebSocketClientMode};
+use turbo_zk_benchmark::http2_benchmark::{http2_benchmark, http2_upload_benchmark, Http2Transport};
+use turbo_zk_benchmark::zk_bellman::{zk_bellman_benchmark, Groth16Prover};
+use turbo_zk_benchmark::zk_recursive::{State, StateMachine};
+use turbo_zk_benchmark::transport::{ping_pong, EchoSession, Transport, TransportConfig};
+use turbo_zk_benchmark::reliable_udp::ReliableUdpTransport;
+use turbo_zk_benchmark::udp_ping_pong::UdpTransport;
+use turbo_zk_benchmark::zk_transport::{zk_ping_pong, ProofSystem, ZkBackend};
+use turbo_zk_benchmark::report::{BenchReport, ReportFormat};
+use std::sync::Arc;
+use turbo_zk_benchmark::webrtc_benchmark::WebRtcTransport;
+use turbo_zk_benchmark::websocket::WebSocketTransport;
 
 fn udp_ping_pong_benchmark(c: &mut Criterion) {
     let mut group = c.benchmark_group("udp_ping_pong");
@@ -14,10 +28,29 @@ fn udp_ping_pong_benchmark(c: &mut Criterion) {
     group.bench_function("udp_ping_pong", |b| {
         let rt = Runtime::new().unwrap();
         b.iter(|| {
-            if let Ok((elapsed, total_bytes)) = rt.block_on(udp_ping_pong(black_box(iterations), black_box(msg_size))) {
-                let latency_ms = elapsed.as_nanos() as f64 / 1_000_000.0 / iterations as f64;
-                let throughput_mbps = total_bytes as f64 / elapsed.as_secs_f64() / 1_000_000.0;
-                println!("udp_ping_pong: Latency: {:.2} ms/iter, Throughput: {:.2} MB/s", latency_ms, throughput_mbps);
+            if let Ok(result) = rt.block_on(udp_ping_pong(&TransportConfig::default(), black_box(iterations), black_box(msg_size))) {
+                println!("udp_ping_pong: Latency: {}, Throughput: {:.2} MB/s", result.latency, result.throughput_mbps());
+            } else {
+                println!("Error occurred during UDP ping pong benchmark");
+            }
+        })
+    });
+
+    // 1% loss and 1 ms of latency each way, with lost round trips timing out after 10 ms
+    let impairment = NetworkImpairment { loss: 0.01, latency: Duration::from_millis(1), ..Default::default() };
+    let lossy = TransportConfig {
+        udp: UdpConfig { impairment, recv_timeout: Duration::from_millis(10) },
+        ..Default::default()
+    };
+    group.bench_function("udp_ping_pong_lossy", |b| {
+        let rt = Runtime::new().unwrap();
+        b.iter(|| {
+            if let Ok(result) = rt.block_on(udp_ping_pong(&lossy, black_box(iterations), black_box(msg_size))) {
+                let delivery = result.delivery.unwrap_or_default();
+                println!(
+                    "udp_ping_pong_lossy: Latency: {}, Throughput: {:.2} MB/s, Delivered: {}/{}",
+                    result.latency, result.throughput_mbps(), delivery.delivered, delivery.sent
+                );
             } else {
                 println!("Error occurred during UDP ping pong benchmark");
             }
@@ -36,25 +69,52 @@ fn webrtc_benchmark_fn(c: &mut Criterion) {
 
     // Create a single Tokio runtime outside the loop
     let rt = Runtime::new().unwrap();
-    group.bench_function("webrtc", |b| {
-        b.iter(|| {
-            rt.block_on(async {
-                match webrtc_benchmark(black_box(iterations), black_box(msg_size)).await {
-                    Ok((elapsed, total_bytes)) => {
-                        let latency_ms = elapsed.as_nanos() as f64 / 1_000_000.0 / iterations as f64;
-                        let throughput_mbps = total_bytes as f64 / elapsed.as_secs_f64() / 1_000_000.0;
-                    println!(
-                        "Latency: {:.2} ms/iter, Throughput: {:.2} MB/s",
-                        latency_ms, throughput_mbps
-                    );
-                }
-                Err(e) => {
-                    println!("Error occurred during WebRTC benchmark: {:?}", e);
-                }
-            }
-        })
+    for (name, mode, gathering) in [
+        ("webrtc_rtt", WebRtcBenchMode::RoundTrip, IceGathering::Full),
+        ("webrtc_one_way", WebRtcBenchMode::OneWay, IceGathering::Full),
+        ("webrtc_rtt_trickle", WebRtcBenchMode::RoundTrip, IceGathering::Trickle),
+    ] {
+        let config = WebRtcConfig { gathering, ..Default::default() };
+        group.bench_function(name, |b| {
+            b.iter(|| {
+                rt.block_on(async {
+                    match webrtc_benchmark(&config, mode, black_box(iterations), black_box(msg_size)).await {
+                        Ok(result) => {
+                            println!(
+                                "{}: Latency: {}, Throughput: {:.2} MB/s, Setup: {}",
+                                name, result.latency, result.throughput_mbps(), result.setup.unwrap_or_default()
+                            );
+                        }
+                        Err(e) => {
+                            println!("Error occurred during WebRTC benchmark: {:?}", e);
+                        }
+                    }
+                })
+            });
         });
-    });
+    }
+
+    // Fan out over 4 channels to 3 leaves, and between 4 peers in a full mesh
+    let config = WebRtcConfig { channels: 4, ..Default::default() };
+    for (name, topology) in [("webrtc_fanout_star", WebRtcTopology::Star), ("webrtc_fanout_mesh", WebRtcTopology::Mesh)] {
+        group.bench_function(name, |b| {
+            b.iter(|| {
+                rt.block_on(async {
+                    match webrtc_fanout_benchmark(&config, topology, 4, black_box(iterations), black_box(msg_size)).await {
+                        Ok(result) => {
+                            println!(
+                                "{}: Latency: {}, Throughput: {:.2} MB/s, Fairness: {}",
+                                name, result.latency, result.throughput_mbps(), result.fairness.unwrap_or_default()
+                            );
+                        }
+                        Err(e) => {
+                            println!("Error occurred during WebRTC fanout benchmark: {:?}", e);
+                        }
+                    }
+                })
+            });
+        });
+    }
 
     group.finish();
 }
@@ -63,22 +123,52 @@ fn zk_bellman_benchmark_fn(c: &mut Criterion) {
     let mut group = c.benchmark_group("zk_bellman");
     group.measurement_time(std::time::Duration::from_secs(60));
 
-    let iterations = 100;
-    // let payload_size = 1024;
-    let payload_size = 8192;
+    // Every payload bit goes through SHA-256 in the circuit, so keep proofs few and small
+    let iterations = 10;
+    let payload_size = 1024;
 
     group.bench_function("zk_bellman", |b| {
         b.iter(|| {
-            if let Ok((elapsed, total_bytes)) = zk_bellman_benchmark(black_box(payload_size), black_box(iterations)) {
-                let latency_ms = elapsed.as_nanos() as f64 / 1_000_000.0 / iterations as f64;
-                let throughput_mbps = total_bytes as f64 / elapsed.as_secs_f64() / 1_000_000.0;
-                println!("zk_bellman: Latency: {:.2} ms/iter, Throughput: {:.2} MB/s", latency_ms, throughput_mbps);
-            } else {
-                println!("Error occurred during ZK Bellman benchmark");
+            match zk_bellman_benchmark(black_box(payload_size), black_box(iterations)) {
+                Ok(result) => println!("zk_bellman: {}", result),
+                Err(e) => println!("Error occurred during ZK Bellman benchmark: {:?}", e),
             }
         })
     });
 
+    // Verification cost doesn't depend on the payload size, since the public
+    // inputs are always the digest, so a small circuit keeps setup quick
+    let prover = Groth16Prover::new(64).unwrap();
+    let payloads: Vec<Vec<u8>> = (0..64u8).map(|i| vec![i; 64]).collect();
+    let proofs: Vec<Vec<u8>> = payloads.iter().map(|payload| prover.prove(payload).unwrap()).collect();
+
+    group.bench_function("groth16_verify", |b| {
+        b.iter(|| prover.verify(black_box(&payloads[0]), black_box(&proofs[0])).unwrap())
+    });
+    for batch_size in [8, 64] {
+        let batch: Vec<_> = payloads.iter().zip(&proofs).take(batch_size).map(|(payload, proof)| (&payload[..], &proof[..])).collect();
+        group.bench_function(format!("groth16_batch_verify_{}", batch_size), |b| {
+            b.iter(|| prover.verify_batch(black_box(&batch)).unwrap())
+        });
+    }
+
+    group.finish();
+}
+
+// One plonky2 state transition, compiling the circuit every time or once up front
+fn state_transition_benchmark(c: &mut Criterion) {
+    let mut group = c.benchmark_group("zk_recursive");
+
+    let state = State::new(0, 0);
+    group.bench_function("state_transition_uncached", |b| {
+        b.iter(|| state.move_by(black_box(1), black_box(1)).unwrap())
+    });
+
+    let machine = StateMachine::new().unwrap();
+    group.bench_function("state_transition_cached", |b| {
+        b.iter(|| machine.move_by(&state, black_box(1), black_box(1)).unwrap())
+    });
+
     group.finish();
 }
 
@@ -92,13 +182,11 @@ fn websocket_benchmark_fn(c: &mut Criterion) {
     group.bench_function("websocket", |b| {
         b.iter(|| {
             rt.block_on(async {
-                match websocket_benchmark(black_box(iterations), black_box(msg_size), black_box(100)).await {
-                    Ok((elapsed, total_bytes)) => {
-                        let latency_ms = elapsed.as_nanos() as f64 / 1_000_000.0 / iterations as f64;
-                        let throughput_mbps = total_bytes as f64 / elapsed.as_secs_f64() / 1_000_000.0;
+                match websocket_benchmark(&TransportConfig::default(), WebSocketClientMode::Local, black_box(iterations), black_box(msg_size), black_box(100)).await {
+                    Ok(result) => {
                         println!(
-                            "WebSocket: Latency: {:.2} ms/iter, Throughput: {:.2} MB/s",
-                            latency_ms, throughput_mbps
+                            "WebSocket: Latency: {}, Throughput: {:.2} MB/s",
+                            result.latency, result.throughput_mbps()
                         );
                     }
                     Err(e) => {
@@ -112,5 +200,234 @@ fn websocket_benchmark_fn(c: &mut Criterion) {
     group.finish();
 }
 
-criterion_group!(benches,websocket_benchmark_fn, udp_ping_pong_benchmark, webrtc_benchmark_fn, zk_bellman_benchmark_fn, );
+// Latency against throughput as more round trips are kept in flight
+fn pipelining_benchmark(c: &mut Criterion) {
+    let mut group = c.benchmark_group("pipelining");
+
+    let iterations = 1000;
+    let msg_size = 1024;
+
+    let rt = Runtime::new().unwrap();
+    for window in [1, 4, 16, 64] {
+        let config = TransportConfig { window, ..Default::default() };
+
+        group.bench_function(format!("udp_window_{}", window), |b| {
+            b.iter(|| {
+                match rt.block_on(udp_ping_pong(&config, black_box(iterations), black_box(msg_size))) {
+                    Ok(result) => {
+                        let delivery = result.delivery.unwrap_or_default();
+                        println!(
+                            "udp_window_{}: Latency: {}, Throughput: {:.2} MB/s, Delivered: {}/{}",
+                            window, result.latency, result.throughput_mbps(), delivery.delivered, delivery.sent
+                        );
+                    }
+                    Err(e) => println!("Error occurred during pipelined UDP benchmark: {:?}", e),
+                }
+            })
+        });
+
+        group.bench_function(format!("websocket_window_{}", window), |b| {
+            b.iter(|| {
+                match rt.block_on(websocket_benchmark(&config, WebSocketClientMode::Local, black_box(iterations as usize), black_box(msg_size), iterations as usize)) {
+                    Ok(result) => {
+                        println!(
+                            "websocket_window_{}: Latency: {}, Throughput: {:.2} MB/s",
+                            window, result.latency, result.throughput_mbps()
+                        );
+                    }
+                    Err(e) => println!("Error occurred during pipelined WebSocket benchmark: {:?}", e),
+                }
+            })
+        });
+    }
+
+    group.finish();
+}
+
+fn http2_benchmark_fn(c: &mut Criterion) {
+    let mut group = c.benchmark_group("http2");
+
+    let iterations = 1000;
+    let msg_size = 1024;
+
+    let rt = Runtime::new().unwrap();
+    group.bench_function("http2_ping_pong", |b| {
+        b.iter(|| {
+            rt.block_on(async {
+                match http2_benchmark(&TransportConfig::default(), black_box(iterations), black_box(msg_size)).await {
+                    Ok(result) => {
+                        println!(
+                            "HTTP2: Latency: {}, Throughput: {:.2} MB/s",
+                            result.latency, result.throughput_mbps()
+                        );
+                    }
+                    Err(e) => {
+                        println!("Error occurred during HTTP2 benchmark: {:?}", e);
+                    }
+                }
+            })
+        });
+    });
+    group.bench_function("http2_upload", |b| {
+        b.iter(|| {
+            rt.block_on(async {
+                match http2_upload_benchmark(&TransportConfig::default(), black_box(iterations), black_box(msg_size)).await {
+                    Ok(result) => {
+                        println!("HTTP2 upload: Throughput: {:.2} MB/s", result.throughput_mbps());
+                    }
+                    Err(e) => {
+                        println!("Error occurred during HTTP2 upload benchmark: {:?}", e);
+                    }
+                }
+            })
+        });
+    });
+
+    group.finish();
+}
+
+// Archives reports under target/bench_reports so CI can keep and diff them
+fn write_reports(name: &str, reports: &[BenchReport]) {
+    let dir = std::path::Path::new(env!("CARGO_MANIFEST_DIR")).join("target").join("bench_reports");
+    let result = std::fs::create_dir_all(&dir).map_err(anyhow::Error::from).and_then(|_| {
+        ReportFormat::Json.write(reports, std::fs::File::create(dir.join(format!("{}.json", name)))?)?;
+        ReportFormat::Csv.write(reports, std::fs::File::create(dir.join(format!("{}.csv", name)))?)
+    });
+    if let Err(e) = result {
+        println!("Error occurred while writing {} reports: {:?}", name, e);
+    }
+}
+
+fn bench_transport<T: Transport>(group: &mut criterion::BenchmarkGroup<criterion::measurement::WallTime>, rt: &Runtime, iterations: u64, msg_size: usize) -> Option<BenchReport> {
+    let mut last = None;
+    group.bench_function(T::NAME, |b| {
+        b.iter(|| {
+            rt.block_on(async {
+                match ping_pong::<T>(&TransportConfig::default(), black_box(iterations), black_box(msg_size)).await {
+                    Ok(result) => {
+                        println!(
+                            "{}: Latency: {}, Throughput: {:.2} MB/s",
+                            T::NAME, result.latency, result.throughput_mbps()
+                        );
+                        last = Some(BenchReport::new(T::NAME, None, msg_size, iterations, &result));
+                    }
+                    Err(e) => {
+                        println!("Error occurred during {} ping pong: {:?}", T::NAME, e);
+                    }
+                }
+            })
+        });
+    });
+    last
+}
+
+// Like `bench_transport`, but connects once and reuses the connection for every
+// iteration, so only steady-state transfer is measured
+fn bench_transport_reused<T: Transport>(group: &mut criterion::BenchmarkGroup<criterion::measurement::WallTime>, rt: &Runtime, iterations: u64, msg_size: usize) -> Option<BenchReport> {
+    let mut session = match rt.block_on(EchoSession::<T>::connect(&TransportConfig::default())) {
+        Ok(session) => session,
+        Err(e) => {
+            println!("Error occurred while connecting {}: {:?}", T::NAME, e);
+            return None;
+        }
+    };
+    println!("{} setup: {}", T::NAME, session.setup());
+
+    let name = format!("{} reused", T::NAME);
+    let mut last = None;
+    group.bench_function(&name, |b| {
+        b.iter(|| {
+            rt.block_on(async {
+                match session.ping_pong(black_box(iterations), black_box(msg_size)).await {
+                    Ok(result) => {
+                        println!(
+                            "{}: Latency: {}, Throughput: {:.2} MB/s",
+                            name, result.latency, result.throughput_mbps()
+                        );
+                        let result = result.with_setup(session.setup().clone());
+                        last = Some(BenchReport::new(T::NAME, None, msg_size, iterations, &result));
+                    }
+                    Err(e) => {
+                        println!("Error occurred during {} ping pong: {:?}", name, e);
+                    }
+                }
+            })
+        });
+    });
+
+    if let Err(e) = rt.block_on(session.close()) {
+        println!("Error occurred while closing {}: {:?}", T::NAME, e);
+    }
+    last
+}
+
+fn transport_ping_pong_benchmark(c: &mut Criterion) {
+    let mut group = c.benchmark_group("transport_ping_pong");
+
+    let iterations = 1000;
+    let msg_size = 1024;
+
+    let rt = Runtime::new().unwrap();
+    let reports = [
+        bench_transport::<UdpTransport>(&mut group, &rt, iterations, msg_size),
+        bench_transport::<ReliableUdpTransport>(&mut group, &rt, iterations, msg_size),
+        bench_transport::<WebSocketTransport>(&mut group, &rt, iterations, msg_size),
+        bench_transport::<Http2Transport>(&mut group, &rt, iterations, msg_size),
+        bench_transport::<WebRtcTransport>(&mut group, &rt, iterations, msg_size),
+        bench_transport_reused::<UdpTransport>(&mut group, &rt, iterations, msg_size),
+        bench_transport_reused::<ReliableUdpTransport>(&mut group, &rt, iterations, msg_size),
+        bench_transport_reused::<WebSocketTransport>(&mut group, &rt, iterations, msg_size),
+        bench_transport_reused::<Http2Transport>(&mut group, &rt, iterations, msg_size),
+        bench_transport_reused::<WebRtcTransport>(&mut group, &rt, iterations, msg_size),
+    ];
+
+    group.finish();
+    write_reports("transport_ping_pong", &reports.into_iter().flatten().collect::<Vec<_>>());
+}
+
+fn bench_zk_transport<T: Transport>(group: &mut criterion::BenchmarkGroup<criterion::measurement::WallTime>, rt: &Runtime, backend: ZkBackend, prover: &Arc<dyn ProofSystem>, iterations: u64, msg_size: usize) -> Option<BenchReport> {
+    let mut last = None;
+    group.bench_function(format!("{}/{}", T::NAME, backend.name()), |b| {
+        b.iter(|| {
+            rt.block_on(async {
+                match zk_ping_pong::<T>(&TransportConfig::default(), black_box(iterations), black_box(msg_size), prover.clone()).await {
+                    Ok(result) => {
+                        println!(
+                            "{} + {}: Latency: {}, Throughput: {:.2} MB/s",
+                            T::NAME, backend.name(), result.latency, result.throughput_mbps()
+                        );
+                        last = Some(BenchReport::new(T::NAME, Some(backend), msg_size, iterations, &result));
+                    }
+                    Err(e) => {
+                        println!("Error occurred during {} + {} ping pong: {:?}", T::NAME, backend.name(), e);
+                    }
+                }
+            })
+        });
+    });
+    last
+}
+
+fn zk_transport_benchmark(c: &mut Criterion) {
+    let mut group = c.benchmark_group("zk_transport_ping_pong");
+    group.sample_size(10);
+
+    let iterations = 10;
+    let msg_size = 256;
+
+    let rt = Runtime::new().unwrap();
+    let mut reports = Vec::new();
+    for backend in [ZkBackend::Groth16, ZkBackend::Plonky2] {
+        let prover = backend.prover(msg_size).unwrap();
+        reports.extend(bench_zk_transport::<UdpTransport>(&mut group, &rt, backend, &prover, iterations, msg_size));
+        reports.extend(bench_zk_transport::<WebSocketTransport>(&mut group, &rt, backend, &prover, iterations, msg_size));
+        reports.extend(bench_zk_transport::<Http2Transport>(&mut group, &rt, backend, &prover, iterations, msg_size));
+        reports.extend(bench_zk_transport::<WebRtcTransport>(&mut group, &rt, backend, &prover, iterations, msg_size));
+    }
+
+    group.finish();
+    write_reports("zk_transport_ping_pong", &reports);
+}
+
+criterion_group!(benches,websocket_benchmark_fn, udp_ping_pong_benchmark, webrtc_benchmark_fn, zk_bellman_benchmark_fn, http2_benchmark_fn, transport_ping_pong_benchmark, zk_transport_benchmark, pipelining_benchmark, state_transition_benchmark, );
 criterion_main!(benches); 
@@ -0,0 +1,252 @@
+use std::time::Instant;
+use anyhow::{anyhow, Result};
+use async_trait::async_trait;
+use bytes::{Buf, Bytes, BytesMut};
+use futures_util::future::poll_fn;
+use h2::{RecvStream, SendStream};
+use http::{Method, Request, Response};
+use std::net::SocketAddr;
+
+use crate::stats::{BenchResult, LatencyHistogram};
+use crate::transport::{bind_tcp, connect_tcp, Transport, TransportConfig};
+
+/// Request/response ping-pong: every iteration POSTs `msg_size` bytes on a new
+/// stream and waits for the server to echo the body back.
+pub async fn http2_benchmark(config: &TransportConfig, iterations: u64, msg_size: usize) -> Result<BenchResult> {
+    let listener = bind_tcp(config.server_addr).await?;
+    let addr = listener.local_addr()?;
+
+    let server = tokio::spawn(async move {
+        let (socket, _) = listener.accept().await?;
+        let mut conn = h2::server::handshake(socket).await?;
+        while let Some(request) = conn.accept().await {
+            let (request, mut respond) = request?;
+            tokio::spawn(async move {
+                let body = read_body(request.into_body()).await?;
+                let mut send = respond.send_response(Response::new(()), false)?;
+                send_body(&mut send, body, true).await
+            });
+        }
+        Result::<()>::Ok(())
+    });
+
+    let socket = connect_tcp(config.client_addr, addr).await?;
+    let (client, connection) = h2::client::handshake(socket).await?;
+    tokio::spawn(connection);
+
+    let msg = Bytes::from(vec![0u8; msg_size]);
+    let mut total_bytes = 0;
+    let mut histogram = LatencyHistogram::with_capacity(iterations as usize);
+
+    let start = Instant::now();
+    for _ in 0..iterations {
+        let sent = Instant::now();
+        let mut client = client.clone().ready().await?;
+        let request = Request::builder()
+            .method(Method::POST)
+            .uri(format!("http://{}/echo", addr))
+            .body(())?;
+        let (response, mut send) = client.send_request(request, false)?;
+        send_body(&mut send, msg.clone(), true).await?;
+
+        let body = read_body(response.await?.into_body()).await?;
+        histogram.record(sent.elapsed());
+        total_bytes += msg.len() + body.len();
+    }
+    let elapsed = start.elapsed();
+
+    drop(client);
+    server.await??;
+
+    Ok(BenchResult::new(elapsed, total_bytes, &histogram).with_server_addr(addr))
+}
+
+/// Streaming upload: sends `iterations` chunks of `msg_size` bytes as the body of
+/// a single request, then waits for the server to acknowledge the byte count.
+/// The whole upload is recorded as a single round trip.
+pub async fn http2_upload_benchmark(config: &TransportConfig, iterations: u64, msg_size: usize) -> Result<BenchResult> {
+    let listener = bind_tcp(config.server_addr).await?;
+    let addr = listener.local_addr()?;
+
+    let server = tokio::spawn(async move {
+        let (socket, _) = listener.accept().await?;
+        let mut conn = h2::server::handshake(socket).await?;
+        while let Some(request) = conn.accept().await {
+            let (request, mut respond) = request?;
+            tokio::spawn(async move {
+                let mut body = request.into_body();
+                let mut received = 0u64;
+                while let Some(chunk) = body.data().await {
+                    let chunk = chunk?;
+                    body.flow_control().release_capacity(chunk.len())?;
+                    received += chunk.len() as u64;
+                }
+                let mut send = respond.send_response(Response::new(()), false)?;
+                send_body(&mut send, Bytes::copy_from_slice(&received.to_be_bytes()), true).await
+            });
+        }
+        Result::<()>::Ok(())
+    });
+
+    let socket = connect_tcp(config.client_addr, addr).await?;
+    let (client, connection) = h2::client::handshake(socket).await?;
+    tokio::spawn(connection);
+
+    let msg = Bytes::from(vec![0u8; msg_size]);
+
+    let start = Instant::now();
+    let mut client = client.ready().await?;
+    let request = Request::builder()
+        .method(Method::POST)
+        .uri(format!("http://{}/upload", addr))
+        .body(())?;
+    // An empty upload ends the body with the headers
+    let (response, mut send) = client.send_request(request, iterations == 0)?;
+    for i in 0..iterations {
+        send_body(&mut send, msg.clone(), i + 1 == iterations).await?;
+    }
+
+    let mut ack = read_body(response.await?.into_body()).await?;
+    if ack.len() != 8 {
+        return Err(anyhow!("Unexpected upload acknowledgement of {} bytes", ack.len()));
+    }
+    let total_bytes = ack.get_u64() as usize;
+    let elapsed = start.elapsed();
+    let mut histogram = LatencyHistogram::with_capacity(1);
+    histogram.record(elapsed);
+
+    drop(send);
+    drop(client);
+    server.await??;
+
+    Ok(BenchResult::new(elapsed, total_bytes, &histogram).with_server_addr(addr))
+}
+
+// Sends `data` on `stream`, waiting for flow-control capacity as needed
+async fn send_body(stream: &mut SendStream<Bytes>, mut data: Bytes, end_of_stream: bool) -> Result<()> {
+    if data.is_empty() {
+        stream.send_data(data, end_of_stream)?;
+        return Ok(());
+    }
+    while !data.is_empty() {
+        stream.reserve_capacity(data.len());
+        let capacity = poll_fn(|cx| stream.poll_capacity(cx))
+            .await
+            .ok_or_else(|| anyhow!("HTTP/2 stream closed"))??;
+        let chunk = data.split_to(capacity.min(data.len()));
+        stream.send_data(chunk, end_of_stream && data.is_empty())?;
+    }
+    Ok(())
+}
+
+// Reads the remainder of a body, releasing flow-control capacity as it goes
+async fn read_body(mut body: RecvStream) -> Result<Bytes> {
+    let mut data = BytesMut::new();
+    while let Some(chunk) = body.data().await {
+        let chunk = chunk?;
+        body.flow_control().release_capacity(chunk.len())?;
+        data.extend_from_slice(&chunk);
+    }
+    Ok(data.freeze())
+}
+
+/// A single long-lived HTTP/2 stream used in both directions.
+///
+/// Messages are length-prefixed, since DATA frames may be split or coalesced.
+pub struct Http2Transport {
+    send: SendStream<Bytes>,
+    recv: RecvStream,
+    buf: BytesMut,
+    local_addr: Option<SocketAddr>,
+}
+
+#[async_trait]
+impl Transport for Http2Transport {
+    const NAME: &'static str = "HTTP2";
+
+    async fn connect(config: &TransportConfig) -> Result<(Self, Self)> {
+        let listener = bind_tcp(config.server_addr).await?;
+        let addr = listener.local_addr()?;
+
+        let server = async {
+            let (socket, _) = listener.accept().await?;
+            let local_addr = socket.local_addr().ok();
+            let mut conn = h2::server::handshake(socket).await?;
+            let (request, mut respond) = conn
+                .accept()
+                .await
+                .ok_or_else(|| anyhow!("HTTP/2 client went away before opening a stream"))??;
+            let send = respond.send_response(Response::new(()), false)?;
+            // Keep driving the connection for as long as the stream is in use
+            tokio::spawn(async move { while let Some(Ok(_)) = conn.accept().await {} });
+            Result::<_>::Ok(Self { send, recv: request.into_body(), buf: BytesMut::new(), local_addr })
+        };
+        let client = async {
+            let socket = connect_tcp(config.client_addr, addr).await?;
+            let local_addr = socket.local_addr().ok();
+            let (client, connection) = h2::client::handshake(socket).await?;
+            tokio::spawn(connection);
+
+            let mut client = client.ready().await?;
+            let request = Request::builder()
+                .method(Method::POST)
+                .uri(format!("http://{}/stream", addr))
+                .body(())?;
+            let (response, send) = client.send_request(request, false)?;
+            let recv = response.await?.into_body();
+            Result::<_>::Ok(Self { send, recv, buf: BytesMut::new(), local_addr })
+        };
+        let (server, client) = tokio::try_join!(server, client)?;
+
+        Ok((client, server))
+    }
+
+    async fn send(&mut self, msg: &[u8]) -> Result<()> {
+        let mut frame = BytesMut::with_capacity(4 + msg.len());
+        frame.extend_from_slice(&(msg.len() as u32).to_be_bytes());
+        frame.extend_from_slice(msg);
+        send_body(&mut self.send, frame.freeze(), false).await
+    }
+
+    async fn recv(&mut self) -> Result<Vec<u8>> {
+        loop {
+            if self.buf.len() >= 4 {
+                let len = u32::from_be_bytes(self.buf[..4].try_into()?) as usize;
+                if self.buf.len() >= 4 + len {
+                    self.buf.advance(4);
+                    return Ok(self.buf.split_to(len).to_vec());
+                }
+            }
+            let chunk = self
+                .recv
+                .data()
+                .await
+                .ok_or_else(|| anyhow!("HTTP/2 stream closed"))??;
+            self.recv.flow_control().release_capacity(chunk.len())?;
+            self.buf.extend_from_slice(&chunk);
+        }
+    }
+
+    async fn close(mut self) -> Result<()> {
+        self.send.send_data(Bytes::new(), true)?;
+        Ok(())
+    }
+
+    fn local_addr(&self) -> Option<SocketAddr> {
+        self.local_addr
+    }
+}
+
+#[tokio::test]
+async fn test_http2_benchmarks() -> Result<()> {
+    let config = TransportConfig::default();
+    let result = http2_benchmark(&config, 10, 1024).await?;
+    assert_eq!(result.total_bytes, 10 * 1024 * 2);
+
+    let result = http2_upload_benchmark(&config, 100, 64 * 1024).await?;
+    assert_eq!(result.total_bytes, 100 * 64 * 1024);
+    let result = http2_upload_benchmark(&config, 0, 64 * 1024).await?;
+    assert_eq!(result.total_bytes, 0);
+
+    Ok(())
+}
@@ -0,0 +1,88 @@
+use anyhow::Result;
+use std::net::SocketAddr;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::net::UdpSocket;
+use webrtc::ice_transport::ice_credential_type::RTCIceCredentialType;
+use webrtc::ice_transport::ice_server::RTCIceServer;
+use webrtc::turn::auth::{generate_auth_key, AuthHandler};
+use webrtc::turn::relay::relay_static::RelayAddressGeneratorStatic;
+use webrtc::turn::server::config::{ConnConfig, ServerConfig};
+use webrtc::turn::server::Server;
+use webrtc::util::vnet::net::Net;
+
+const REALM: &str = "turbo_zk_benchmark";
+const USERNAME: &str = "bench";
+const PASSWORD: &str = "bench";
+
+/// A TURN server running inside this process, so server-reflexive and relayed
+/// candidates can be exercised without network access.
+///
+/// TURN servers also answer plain STUN binding requests, so the same server
+/// backs both [`LocalIceServer::stun_server`] and [`LocalIceServer::turn_server`].
+pub struct LocalIceServer {
+    server: Server,
+    addr: SocketAddr,
+}
+
+impl LocalIceServer {
+    /// Starts the server on `addr`. Relayed traffic is allocated on the same IP.
+    pub async fn start(addr: SocketAddr) -> Result<Self> {
+        let conn = Arc::new(UdpSocket::bind(addr).await?);
+        let addr = conn.local_addr()?;
+
+        let server = Server::new(ServerConfig {
+            conn_configs: vec![ConnConfig {
+                conn,
+                relay_addr_generator: Box::new(RelayAddressGeneratorStatic {
+                    relay_address: addr.ip(),
+                    address: addr.ip().to_string(),
+                    net: Arc::new(Net::new(None)),
+                }),
+            }],
+            realm: REALM.to_owned(),
+            auth_handler: Arc::new(StaticAuthHandler),
+            channel_bind_timeout: Duration::from_secs(0),
+            alloc_close_notify: None,
+        })
+        .await?;
+
+        Ok(Self { server, addr })
+    }
+
+    pub fn addr(&self) -> SocketAddr {
+        self.addr
+    }
+
+    /// ICE server entry for server-reflexive candidates only.
+    pub fn stun_server(&self) -> RTCIceServer {
+        RTCIceServer { urls: vec![format!("stun:{}", self.addr)], ..Default::default() }
+    }
+
+    /// ICE server entry for relayed candidates, with credentials filled in.
+    pub fn turn_server(&self) -> RTCIceServer {
+        RTCIceServer {
+            urls: vec![format!("turn:{}?transport=udp", self.addr)],
+            username: USERNAME.to_owned(),
+            credential: PASSWORD.to_owned(),
+            credential_type: RTCIceCredentialType::Password,
+        }
+    }
+
+    pub async fn close(&self) -> Result<()> {
+        self.server.close().await?;
+        Ok(())
+    }
+}
+
+// Accepts the single built-in user
+struct StaticAuthHandler;
+
+impl AuthHandler for StaticAuthHandler {
+    fn auth_handle(&self, username: &str, realm: &str, _src_addr: SocketAddr) -> Result<Vec<u8>, webrtc::turn::Error> {
+        if username != USERNAME {
+            return Err(webrtc::turn::Error::ErrNoSuchUser);
+        }
+        Ok(generate_auth_key(username, realm, PASSWORD))
+    }
+}
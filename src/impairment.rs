@@ -0,0 +1,200 @@
+use anyhow::{anyhow, Result};
+use rand::rngs::StdRng;
+use rand::{Rng, SeedableRng};
+use std::io;
+use std::net::SocketAddr;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use tokio::net::UdpSocket;
+
+/// Network conditions to simulate on loopback. The default leaves traffic untouched.
+///
+/// Each datagram is first dropped, duplicated or passed on, then queued on a
+/// link limited to `rate_limit`, and finally delayed by `latency` plus jitter.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct NetworkImpairment {
+    /// Fraction of datagrams dropped, from 0.0 to 1.0.
+    pub loss: f64,
+    /// Delay added to every datagram.
+    pub latency: Duration,
+    /// Up to this much extra delay, drawn uniformly for every datagram. Enough
+    /// jitter reorders datagrams sent close together.
+    pub jitter: Duration,
+    /// Fraction of datagrams sent without `latency` or jitter, so they overtake
+    /// the ones still delayed. Has no effect without any delay.
+    pub reorder: f64,
+    /// Fraction of datagrams sent twice.
+    pub duplicate: f64,
+    /// Link capacity in bytes per second. Datagrams wait for the link to be
+    /// free, with no limit on how many can queue.
+    pub rate_limit: Option<u64>,
+    /// Seed for the random choices, to repeat a run exactly.
+    pub seed: Option<u64>,
+}
+
+impl NetworkImpairment {
+    /// The same conditions for the opposite direction of a link. The seed is
+    /// changed, so the two directions drop and delay independently.
+    pub fn reversed(&self) -> Self {
+        Self { seed: self.seed.map(|seed| seed ^ 1), ..*self }
+    }
+
+    fn validate(&self) -> Result<()> {
+        for (name, fraction) in [("loss", self.loss), ("reorder", self.reorder), ("duplicate", self.duplicate)] {
+            if !(0.0..=1.0).contains(&fraction) {
+                return Err(anyhow!("{} must be between 0 and 1, got {}", name, fraction));
+            }
+        }
+        if self.rate_limit == Some(0) {
+            return Err(anyhow!("rate limit must be above zero"));
+        }
+        Ok(())
+    }
+}
+
+/// A UDP socket whose outgoing datagrams go through a [`NetworkImpairment`].
+///
+/// Receiving is untouched, so impairing both ends of a connection impairs both
+/// directions. Delayed datagrams are sent from background tasks, and errors
+/// sending them are ignored the way a real network would drop them.
+pub struct ImpairedUdpSocket {
+    socket: Arc<UdpSocket>,
+    impairment: NetworkImpairment,
+    link: Mutex<Link>,
+}
+
+struct Link {
+    rng: StdRng,
+    /// When the last queued datagram finishes going out at `rate_limit`.
+    busy_until: Instant,
+}
+
+impl ImpairedUdpSocket {
+    pub fn new(socket: UdpSocket, impairment: NetworkImpairment) -> Result<Self> {
+        impairment.validate()?;
+        let rng = match impairment.seed {
+            Some(seed) => StdRng::seed_from_u64(seed),
+            None => StdRng::from_entropy(),
+        };
+        Ok(Self {
+            socket: Arc::new(socket),
+            impairment,
+            link: Mutex::new(Link { rng, busy_until: Instant::now() }),
+        })
+    }
+
+    pub fn local_addr(&self) -> io::Result<SocketAddr> {
+        self.socket.local_addr()
+    }
+
+    /// Sets the peer for [`ImpairedUdpSocket::send`] and [`ImpairedUdpSocket::recv`].
+    pub async fn connect(&self, addr: SocketAddr) -> io::Result<()> {
+        self.socket.connect(addr).await
+    }
+
+    pub async fn send_to(&self, buf: &[u8], target: SocketAddr) -> io::Result<()> {
+        self.send_impaired(buf, Some(target)).await
+    }
+
+    /// Sends to the connected peer.
+    pub async fn send(&self, buf: &[u8]) -> io::Result<()> {
+        self.send_impaired(buf, None).await
+    }
+
+    pub async fn recv_from(&self, buf: &mut [u8]) -> io::Result<(usize, SocketAddr)> {
+        self.socket.recv_from(buf).await
+    }
+
+    pub async fn recv(&self, buf: &mut [u8]) -> io::Result<usize> {
+        self.socket.recv(buf).await
+    }
+
+    async fn send_impaired(&self, buf: &[u8], target: Option<SocketAddr>) -> io::Result<()> {
+        for at in self.schedule(buf.len()) {
+            if at <= Instant::now() {
+                send(&self.socket, buf, target).await?;
+                continue;
+            }
+
+            let socket = self.socket.clone();
+            let buf = buf.to_vec();
+            tokio::spawn(async move {
+                tokio::time::sleep_until(at.into()).await;
+                let _ = send(&socket, &buf, target).await;
+            });
+        }
+        Ok(())
+    }
+
+    // When each copy of a `len` byte datagram should go out: none if it is lost
+    fn schedule(&self, len: usize) -> Vec<Instant> {
+        let impairment = &self.impairment;
+        let mut link = self.link.lock().unwrap();
+        if link.rng.gen_bool(impairment.loss) {
+            return Vec::new();
+        }
+
+        let copies = if link.rng.gen_bool(impairment.duplicate) { 2 } else { 1 };
+        let now = Instant::now();
+        (0..copies)
+            .map(|_| {
+                let mut at = now;
+                if let Some(rate) = impairment.rate_limit {
+                    link.busy_until = link.busy_until.max(now) + Duration::from_secs_f64(len as f64 / rate as f64);
+                    at = link.busy_until;
+                }
+                if !link.rng.gen_bool(impairment.reorder) {
+                    at += impairment.latency + impairment.jitter.mul_f64(link.rng.gen());
+                }
+                at
+            })
+            .collect()
+    }
+}
+
+async fn send(socket: &UdpSocket, buf: &[u8], target: Option<SocketAddr>) -> io::Result<()> {
+    match target {
+        Some(target) => socket.send_to(buf, target).await?,
+        None => socket.send(buf).await?,
+    };
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_impaired_udp_socket() -> Result<()> {
+    let receiver = UdpSocket::bind("127.0.0.1:0").await?;
+    let addr = receiver.local_addr()?;
+    let mut buf = [0; 16];
+
+    // Every datagram is delayed and arrives twice
+    let impairment = NetworkImpairment { latency: Duration::from_millis(30), duplicate: 1.0, ..Default::default() };
+    let sender = ImpairedUdpSocket::new(UdpSocket::bind("127.0.0.1:0").await?, impairment)?;
+    let sent = Instant::now();
+    sender.send_to(b"hello", addr).await?;
+    for _ in 0..2 {
+        let (len, _) = receiver.recv_from(&mut buf).await?;
+        assert_eq!(&buf[..len], b"hello");
+        assert!(sent.elapsed() >= Duration::from_millis(30));
+    }
+
+    // Nothing gets through a link that drops everything
+    let impairment = NetworkImpairment { loss: 1.0, ..Default::default() };
+    let sender = ImpairedUdpSocket::new(UdpSocket::bind("127.0.0.1:0").await?, impairment)?;
+    sender.send_to(b"lost", addr).await?;
+    assert!(tokio::time::timeout(Duration::from_millis(50), receiver.recv_from(&mut buf)).await.is_err());
+
+    // Both directions of a seeded link are repeatable, but don't lose the same datagrams
+    let impairment = NetworkImpairment { loss: 0.5, seed: Some(7), ..Default::default() };
+    let mut losses = Vec::new();
+    for impairment in [impairment, impairment, impairment.reversed()] {
+        let sender = ImpairedUdpSocket::new(UdpSocket::bind("127.0.0.1:0").await?, impairment)?;
+        losses.push((0..32).map(|_| sender.schedule(64).is_empty()).collect::<Vec<_>>());
+    }
+    assert_eq!(losses[0], losses[1]);
+    assert_ne!(losses[0], losses[2]);
+
+    let impairment = NetworkImpairment { loss: 1.5, ..Default::default() };
+    assert!(ImpairedUdpSocket::new(UdpSocket::bind("127.0.0.1:0").await?, impairment).is_err());
+
+    Ok(())
+}
@@ -2,12 +2,32 @@ pub mod udp_ping_pong;
 pub mod webrtc_benchmark;
 pub mod zk_bellman;
 pub mod websocket;
+pub mod http2_benchmark;
 pub mod zk_recursive;
+pub mod transport;
+pub mod zk_transport;
+pub mod stats;
+pub mod report;
+pub mod matrix;
+pub mod ice_server;
+pub mod signaling;
+pub mod impairment;
+pub mod reliable_udp;
 
 
 
 pub use udp_ping_pong::{*};
 pub use webrtc_benchmark::{*};
 pub use websocket::{*};
+pub use http2_benchmark::{*};
 pub use zk_bellman::{*};
-pub use zk_recursive::{*};
\ No newline at end of file
+pub use zk_recursive::{*};
+pub use transport::{*};
+pub use zk_transport::{*};
+pub use stats::{*};
+pub use report::{*};
+pub use matrix::{*};
+pub use ice_server::{*};
+pub use signaling::{*};
+pub use impairment::{*};
+pub use reliable_udp::{*};
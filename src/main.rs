@@ -1,93 +1,453 @@
-use tokio::net::TcpListener;
-use tokio_tungstenite::{accept_async, connect_async, tungstenite::Message};
-use futures_util::{SinkExt, StreamExt};
-use url::Url;
+use anyhow::{anyhow, Context, Result};
+use clap::{Args, Parser, Subcommand};
+use std::net::SocketAddr;
+use std::path::PathBuf;
 use std::time::{Duration, Instant};
-const MESSAGE_SIZE_BYTES: usize = 32; // Size of the ping message in bytes
-
-async fn ping_pong_websocket() {
-    let handles = vec![
-        tokio::spawn(async move {
-            // server thread
-            let listener = TcpListener::bind("127.0.0.1:9001").await.unwrap();
-            println!("WebSocket server listening on ws://127.0.0.1:9001");
-
-            while let Ok((stream, _)) = listener.accept().await {
-                let ws_stream = accept_async(stream).await.unwrap();
-                let (mut write, mut read) = ws_stream.split();
-
-                loop {
-                    // Server sends a ping
-                    let start = Instant::now();
-                    write.send(Message::Ping(vec![0; MESSAGE_SIZE_BYTES])).await.unwrap();
-                    
-                    // Wait for a pong from the client
-                    if let Some(msg) = read.next().await {
-                        match msg {
-                            Ok(Message::Pong(_)) => {
-                                // send a ping back to the client
-                                write.send(Message::Ping(vec![0; MESSAGE_SIZE_BYTES])).await.unwrap();
-                            }
-                            Ok(_) => {}
-                            Err(e) => {
-                                println!("Server error: {:?}", e);
-                                break;
-                            }
-                        }
-                    }
-                }
-                // Close the connection properly
-                write.close().await.unwrap();
+use turbo_zk_benchmark::http2_benchmark::Http2Transport;
+use turbo_zk_benchmark::matrix::{markdown_table, replace_readme_table, run_matrix, MatrixConfig};
+use turbo_zk_benchmark::reliable_udp::{ReliableUdpConfig, ReliableUdpTransport};
+use turbo_zk_benchmark::report::{BenchReport, ReportFormat};
+use turbo_zk_benchmark::transport::{ping_pong, Transport, TransportConfig};
+use turbo_zk_benchmark::impairment::NetworkImpairment;
+use turbo_zk_benchmark::udp_ping_pong::{udp_ping_pong, UdpConfig, UdpTransport};
+use turbo_zk_benchmark::signaling::{LineSignaling, Signaling, SignalingServer, WebSocketSignaling};
+use turbo_zk_benchmark::webrtc_benchmark::{
+    webrtc_benchmark, webrtc_echo_peer, webrtc_fanout_benchmark, webrtc_remote_benchmark, ChannelReliability, IceGathering, IceMode, VirtualNetwork, WebRtcBenchMode, WebRtcConfig, WebRtcTopology, WebRtcTransport,
+};
+use turbo_zk_benchmark::websocket::{websocket_benchmark, websocket_echo_client, WebSocketClientMode, WebSocketTransport};
+use turbo_zk_benchmark::zk_transport::{proof_benchmark, zk_ping_pong, ZkBackend};
+
+/// Transport and zero-knowledge proof benchmarks.
+#[derive(Parser)]
+#[command(version, about)]
+struct Cli {
+    #[command(subcommand)]
+    command: Command,
+
+    #[command(flatten)]
+    options: Options,
+}
+
+#[derive(Subcommand)]
+enum Command {
+    #[command(flatten)]
+    Bench(BenchCommand),
+    #[command(flatten)]
+    Tool(ToolCommand),
+}
+
+/// Subcommands that produce benchmark reports.
+#[derive(Subcommand)]
+enum BenchCommand {
+    /// UDP ping-pong
+    Udp,
+    /// Ping-pong over UDP with sequencing, ACKs and retransmission
+    Rudp,
+    /// WebSocket ping-pong
+    Ws {
+        /// Wait for an external client (see `ws-client`) instead of spawning one
+        #[arg(long)]
+        remote: bool,
+    },
+    /// WebRTC data channel ping-pong
+    Webrtc {
+        /// Send back to back and time arrival at the far end instead of round trips
+        #[arg(long)]
+        one_way: bool,
+    },
+    /// Send one way from many WebRTC peers at once over --channels data channels each
+    WebrtcFanout {
+        /// Number of peers, including the hub of a star
+        #[arg(long, default_value_t = 4)]
+        peers: usize,
+
+        /// star (the first peer sends to all others) or mesh (everyone sends to everyone)
+        #[arg(long, default_value = "star")]
+        topology: WebRtcTopology,
+    },
+    /// Offer a WebRTC connection and time round trips against `webrtc-answer`
+    WebrtcOffer {
+        #[command(flatten)]
+        signaling: SignalingArgs,
+    },
+    /// HTTP/2 ping-pong over a single stream
+    Http2,
+    /// Groth16 prove + verify, no transport
+    Groth16,
+    /// Plonky2 prove + verify, no transport
+    Plonky2,
+    /// Every transport plain and proof-carrying, then both provers
+    All,
+    /// Run every transport plain and proof-carrying and print the readme table
+    Readme {
+        /// Rewrite the table in --file in place
+        #[arg(long)]
+        write: bool,
+
+        /// Readme to rewrite with --write
+        #[arg(long, default_value = "readme.md")]
+        file: PathBuf,
+    },
+}
+
+/// Subcommands that serve the other end of a benchmark instead of reporting.
+#[derive(Subcommand)]
+enum ToolCommand {
+    /// Echo client for `ws --remote` running on another host
+    WsClient {
+        /// Server URL, e.g. ws://10.0.0.1:9001
+        url: String,
+    },
+    /// Answer `webrtc-offer` and echo its messages until it hangs up
+    WebrtcAnswer {
+        #[command(flatten)]
+        signaling: SignalingArgs,
+    },
+    /// Relay signaling between pairs of WebRTC peers, listening on --bind
+    SignalingServer,
+}
+
+#[derive(Args)]
+struct SignalingArgs {
+    /// URL of a `signaling-server` (ws://host:port), or "stdio" to copy and paste
+    #[arg(long, default_value = "stdio")]
+    signaling: String,
+}
+
+impl SignalingArgs {
+    async fn connect(&self) -> Result<Box<dyn Signaling>> {
+        if self.signaling == "stdio" {
+            eprintln!("Paste the line the other peer prints here, and its reply there");
+            Ok(Box::new(LineSignaling::stdio()))
+        } else {
+            Ok(Box::new(WebSocketSignaling::connect(&self.signaling).await?))
+        }
+    }
+}
+
+#[derive(Args)]
+struct Options {
+    /// Round trips per plain transport run
+    #[arg(long, global = true, default_value_t = 1000)]
+    iterations: u64,
+
+    /// Round trips per proof-carrying run, and proofs per prover run
+    #[arg(long, global = true, default_value_t = 20)]
+    zk_iterations: u64,
+
+    /// Message (and proof payload) size in bytes
+    #[arg(long, global = true, default_value_t = 1024)]
+    msg_size: usize,
+
+    /// Messages plain `udp` and `ws` runs keep in flight; a comma-separated list runs each in turn
+    #[arg(long, global = true, value_delimiter = ',', default_value = "1")]
+    window: Vec<usize>,
+
+    /// Keep repeating the run until this many seconds have passed
+    #[arg(long, global = true)]
+    duration: Option<f64>,
+
+    /// Output format: text, json or csv
+    #[arg(long, global = true, default_value = "text")]
+    format: ReportFormat,
+
+    /// Attach proofs from this backend (groth16 or plonky2) to transport traffic
+    #[arg(long, global = true)]
+    zk: Option<ZkBackend>,
+
+    /// Address the server end binds to
+    #[arg(long, global = true, default_value = "127.0.0.1:0")]
+    bind: SocketAddr,
+
+    /// Address the client end binds to
+    #[arg(long, global = true, default_value = "127.0.0.1:0")]
+    client_bind: SocketAddr,
+
+    /// WebRTC candidates: host (no ICE servers), or stun / turn via an embedded server
+    #[arg(long, global = true, default_value = "host")]
+    ice: IceMode,
+
+    /// WebRTC candidate exchange: full (gather first) or trickle (send as found)
+    #[arg(long, global = true, default_value = "full")]
+    gathering: IceGathering,
+
+    /// Let the WebRTC data channel deliver messages out of order
+    #[arg(long, global = true)]
+    unordered: bool,
+
+    /// Give up on a WebRTC message after this many retransmissions (0 = never retransmit)
+    #[arg(long, global = true, conflicts_with = "max_packet_life_time")]
+    max_retransmits: Option<u16>,
+
+    /// Give up on a WebRTC message once it is this many milliseconds old
+    #[arg(long, global = true)]
+    max_packet_life_time: Option<u16>,
+
+    /// Data channels per WebRTC connection
+    #[arg(long, global = true, default_value_t = 1)]
+    channels: usize,
+
+    /// Milliseconds to wait for a UDP or unreliable WebRTC message before counting it as lost
+    #[arg(long, global = true, default_value_t = 1000)]
+    recv_timeout: u64,
+
+    /// Most reliable UDP messages in flight without an ACK
+    #[arg(long, global = true, default_value_t = 64)]
+    send_window: usize,
+
+    /// Percentage of UDP datagrams (and WebRTC packets with --vnet) to drop
+    #[arg(long, global = true, default_value_t = 0.0)]
+    loss: f64,
+
+    /// Milliseconds of delay added to every UDP datagram (and WebRTC packet with --vnet)
+    #[arg(long, global = true, default_value_t = 0.0)]
+    latency: f64,
+
+    /// Up to this many milliseconds of random extra delay per UDP datagram (and WebRTC packet with --vnet)
+    #[arg(long, global = true, default_value_t = 0.0)]
+    jitter: f64,
+
+    /// Percentage of UDP datagrams that skip the delay and overtake the others
+    #[arg(long, global = true, default_value_t = 0.0)]
+    reorder: f64,
+
+    /// Percentage of UDP datagrams sent twice
+    #[arg(long, global = true, default_value_t = 0.0)]
+    duplicate: f64,
+
+    /// Cap UDP traffic in each direction at this many MB/s
+    #[arg(long, global = true)]
+    rate_limit: Option<f64>,
+
+    /// Seed for the simulated UDP impairments, to repeat a run exactly
+    #[arg(long, global = true)]
+    seed: Option<u64>,
+
+    /// Run in-process WebRTC peers over a virtual network with --latency, --jitter and --loss
+    #[arg(long, global = true)]
+    vnet: bool,
+}
+
+impl Options {
+    fn transport_config(&self) -> TransportConfig {
+        TransportConfig {
+            server_addr: self.bind,
+            client_addr: self.client_bind,
+            window: 1,
+            udp: self.udp_config(),
+            reliable_udp: ReliableUdpConfig { window: self.send_window, ..Default::default() },
+            webrtc: self.webrtc_config(),
+        }
+    }
+
+    fn udp_config(&self) -> UdpConfig {
+        let impairment = NetworkImpairment {
+            loss: self.loss / 100.0,
+            latency: millis(self.latency),
+            jitter: millis(self.jitter),
+            reorder: self.reorder / 100.0,
+            duplicate: self.duplicate / 100.0,
+            rate_limit: self.rate_limit.map(|mbps| (mbps * 1_000_000.0) as u64),
+            seed: self.seed,
+        };
+        UdpConfig { impairment, recv_timeout: Duration::from_millis(self.recv_timeout) }
+    }
+
+    fn webrtc_config(&self) -> WebRtcConfig {
+        let reliability = match (self.max_retransmits, self.max_packet_life_time) {
+            (Some(n), _) => ChannelReliability::MaxRetransmits(n),
+            (None, Some(ms)) => ChannelReliability::MaxPacketLifeTime(ms),
+            (None, None) => ChannelReliability::Reliable,
+        };
+        WebRtcConfig {
+            ice_mode: self.ice,
+            gathering: self.gathering,
+            ordered: !self.unordered,
+            reliability,
+            channels: self.channels,
+            recv_timeout: Duration::from_millis(self.recv_timeout),
+            vnet: self.vnet.then(|| VirtualNetwork {
+                latency: millis(self.latency),
+                jitter: millis(self.jitter),
+                loss: self.loss / 100.0,
+            }),
+        }
+    }
+
+    fn matrix_config(&self) -> MatrixConfig {
+        MatrixConfig {
+            iterations: self.iterations,
+            zk_iterations: self.zk_iterations,
+            msg_size: self.msg_size,
+            zk_backend: self.zk.unwrap_or(ZkBackend::Groth16),
+            transport: self.transport_config(),
+        }
+    }
+}
+
+// Fractional milliseconds from the command line as a duration
+fn millis(ms: f64) -> Duration {
+    Duration::from_secs_f64(ms.max(0.0) / 1_000.0)
+}
+
+async fn run_transport<T: Transport>(options: &Options) -> Result<BenchReport> {
+    let config = options.transport_config();
+    match options.zk {
+        Some(backend) => {
+            let prover = backend.prover(options.msg_size)?;
+            let result = zk_ping_pong::<T>(&config, options.zk_iterations, options.msg_size, prover).await?;
+            Ok(BenchReport::new(T::NAME, Some(backend), options.msg_size, options.zk_iterations, &result))
+        }
+        None => {
+            let result = ping_pong::<T>(&config, options.iterations, options.msg_size).await?;
+            Ok(BenchReport::new(T::NAME, None, options.msg_size, options.iterations, &result))
+        }
+    }
+}
+
+fn run_prover(backend: ZkBackend, options: &Options) -> Result<BenchReport> {
+    let result = proof_benchmark(backend, options.zk_iterations, options.msg_size)?;
+    Ok(BenchReport::new("none", Some(backend), options.msg_size, options.zk_iterations, &result))
+}
+
+// Report name for a run with `window` messages in flight
+fn windowed(transport: &str, window: usize) -> String {
+    if window == 1 {
+        transport.to_owned()
+    } else {
+        format!("{} window {}", transport, window)
+    }
+}
+
+async fn run_once(command: &BenchCommand, options: &Options) -> Result<Vec<BenchReport>> {
+    Ok(match command {
+        BenchCommand::Udp if options.zk.is_none() => {
+            let mut reports = Vec::new();
+            for &window in &options.window {
+                let config = TransportConfig { window, ..options.transport_config() };
+                let result = udp_ping_pong(&config, options.iterations, options.msg_size).await?;
+                let name = windowed(UdpTransport::NAME, window);
+                reports.push(BenchReport::new(&name, None, options.msg_size, options.iterations, &result));
             }
-        }),
-        tokio::spawn(async move {
-            // client thread
-            let url = Url::parse("ws://127.0.0.1:9001").unwrap();
-            let (ws_stream, _) = connect_async(url.as_str()).await.unwrap();
-            let (mut write, mut read) = ws_stream.split();
-
-            let mut message_count = 0;
-            let start_time = Instant::now();
-
-            loop {
-                // Wait for a ping from the server
-                if let Some(msg) = read.next().await {
-                    match msg {
-                        Ok(Message::Ping(_)) => {
-                            // Respond with a pong
-                            write.send(Message::Pong(vec![0; MESSAGE_SIZE_BYTES])).await.unwrap();
-                            message_count += 1;
-                            let elapsed = start_time.elapsed().as_secs_f64();
-                            let throughput = (message_count * MESSAGE_SIZE_BYTES * 8) as f64 / (elapsed * 1_000_000.0);
-                            println!("Client sent pong, throughput: {:.2} Mb/s", throughput);
-
-                            // Send a ping back to the server
-                            write.send(Message::Ping(vec![0; MESSAGE_SIZE_BYTES])).await.unwrap();
-                        }
-                        Ok(Message::Pong(_)) => {
-                            // Server should not send pongs, ignore
-                        }
-                        Ok(_) => {}
-                        Err(e) => {
-                            println!("Client error: {:?}", e);
-                            break;
-                        }
-                    }
-                }
+            reports
+        }
+        BenchCommand::Udp => vec![run_transport::<UdpTransport>(options).await?],
+        BenchCommand::Rudp => vec![run_transport::<ReliableUdpTransport>(options).await?],
+        // One round trip at a time, or with proofs, goes through the generic transport below
+        BenchCommand::Ws { remote } if *remote || (options.zk.is_none() && options.window != [1]) => {
+            let mode = if *remote { WebSocketClientMode::Remote } else { WebSocketClientMode::Local };
+            let iterations = options.iterations as usize;
+            let print_interval = iterations;
+            let mut reports = Vec::new();
+            for &window in &options.window {
+                let config = TransportConfig { window, ..options.transport_config() };
+                let result = websocket_benchmark(&config, mode, iterations, options.msg_size, print_interval).await?;
+                let name = windowed(WebSocketTransport::NAME, window);
+                reports.push(BenchReport::new(&name, None, options.msg_size, options.iterations, &result));
             }
-            // Close the connection properly
-            write.close().await.unwrap();
-        })
-    ];
+            reports
+        }
+        BenchCommand::Ws { .. } => vec![run_transport::<WebSocketTransport>(options).await?],
+        BenchCommand::Webrtc { one_way } if options.zk.is_none() => {
+            let mode = if *one_way { WebRtcBenchMode::OneWay } else { WebRtcBenchMode::RoundTrip };
+            let result = webrtc_benchmark(&options.webrtc_config(), mode, options.iterations, options.msg_size).await?;
+            vec![BenchReport::new(WebRtcTransport::NAME, None, options.msg_size, options.iterations, &result)]
+        }
+        BenchCommand::Webrtc { .. } => vec![run_transport::<WebRtcTransport>(options).await?],
+        BenchCommand::WebrtcFanout { peers, topology } => {
+            let config = options.webrtc_config();
+            let result =
+                webrtc_fanout_benchmark(&config, *topology, *peers, options.iterations, options.msg_size).await?;
+            let name = format!("{} {} x{}", WebRtcTransport::NAME, topology.name(), peers);
+            vec![BenchReport::new(&name, None, options.msg_size, options.iterations, &result)]
+        }
+        BenchCommand::WebrtcOffer { signaling } => {
+            let mut signaling = signaling.connect().await?;
+            let result = webrtc_remote_benchmark(
+                &options.webrtc_config(),
+                signaling.as_mut(),
+                options.iterations,
+                options.msg_size,
+            )
+            .await?;
+            vec![BenchReport::new(WebRtcTransport::NAME, None, options.msg_size, options.iterations, &result)]
+        }
+        BenchCommand::Http2 => vec![run_transport::<Http2Transport>(options).await?],
+        BenchCommand::Groth16 => vec![run_prover(ZkBackend::Groth16, options)?],
+        BenchCommand::Plonky2 => vec![run_prover(ZkBackend::Plonky2, options)?],
+        BenchCommand::All => {
+            let mut reports = run_matrix(&options.matrix_config()).await?;
+            reports.push(run_prover(ZkBackend::Groth16, options)?);
+            reports.push(run_prover(ZkBackend::Plonky2, options)?);
+            reports
+        }
+        BenchCommand::Readme { .. } => run_matrix(&options.matrix_config()).await?,
+    })
+}
 
-    for handle in handles {
-        handle.await.unwrap();
+async fn run_tool(command: &ToolCommand, options: &Options) -> Result<()> {
+    match command {
+        ToolCommand::WsClient { url } => {
+            let echoed = websocket_echo_client(url).await?;
+            println!("Echoed {} messages", echoed);
+        }
+        ToolCommand::WebrtcAnswer { signaling } => {
+            let mut signaling = signaling.connect().await?;
+            let echoed = webrtc_echo_peer(&options.webrtc_config(), signaling.as_mut()).await?;
+            eprintln!("Echoed {} messages", echoed);
+        }
+        ToolCommand::SignalingServer => {
+            let server = SignalingServer::bind(options.bind).await?;
+            println!("Signaling server listening on: ws://{}", server.addr());
+            tokio::signal::ctrl_c().await?;
+            server.close();
+        }
     }
+    Ok(())
 }
 
 #[tokio::main]
-async fn main() {
-    println!("Hello, world!");
-    ping_pong_websocket().await;
+async fn main() -> Result<()> {
+    let cli = Cli::parse();
+    let options = &cli.options;
+
+    let command = match &cli.command {
+        Command::Bench(command) => command,
+        Command::Tool(command) => return run_tool(command, options).await,
+    };
+    let windowed = matches!(command, BenchCommand::Udp | BenchCommand::Ws { .. }) && options.zk.is_none();
+    if !windowed && options.window != [1] {
+        return Err(anyhow!("--window only applies to udp and ws runs without --zk"));
+    }
+
+    let deadline = match options.duration {
+        Some(secs) => {
+            let duration = Duration::try_from_secs_f64(secs)
+                .map_err(|_| anyhow!("--duration must be a non-negative number of seconds that fits in a duration, got {}", secs))?;
+            Some(Instant::now().checked_add(duration).ok_or_else(|| anyhow!("--duration of {} seconds is too long", secs))?)
+        }
+        None => None,
+    };
+    let mut reports = Vec::new();
+    loop {
+        reports.extend(run_once(command, options).await?);
+        if deadline.is_none_or(|deadline| Instant::now() >= deadline) {
+            break;
+        }
+    }
+
+    if let BenchCommand::Readme { write, file } = command {
+        let table = markdown_table(&reports);
+        print!("{}", table);
+
+        if *write {
+            let readme = std::fs::read_to_string(file)
+                .with_context(|| format!("Failed to read {}", file.display()))?;
+            std::fs::write(file, replace_readme_table(&readme, &table)?)?;
+            println!("Updated {}", file.display());
+        }
+        return Ok(());
+    }
 
+    options.format.write(&reports, std::io::stdout().lock())
 }
@@ -0,0 +1,170 @@
+use anyhow::{anyhow, Result};
+use std::sync::Arc;
+
+use crate::http2_benchmark::Http2Transport;
+use crate::reliable_udp::ReliableUdpTransport;
+use crate::report::BenchReport;
+use crate::transport::{ping_pong, Transport, TransportConfig};
+use crate::udp_ping_pong::UdpTransport;
+use crate::webrtc_benchmark::WebRtcTransport;
+use crate::websocket::WebSocketTransport;
+use crate::zk_transport::{zk_ping_pong, ProofSystem, ZkBackend};
+
+/// Transports in the order they appear in the readme table.
+pub const MATRIX_TRANSPORTS: [&str; 5] = [
+    WebRtcTransport::NAME,
+    UdpTransport::NAME,
+    ReliableUdpTransport::NAME,
+    Http2Transport::NAME,
+    WebSocketTransport::NAME,
+];
+
+/// Settings for a full transport × normal/ZK run.
+#[derive(Clone, Copy, Debug)]
+pub struct MatrixConfig {
+    pub iterations: u64,
+    /// Proof-carrying runs are far slower, so they get their own iteration count.
+    pub zk_iterations: u64,
+    pub msg_size: usize,
+    pub zk_backend: ZkBackend,
+    pub transport: TransportConfig,
+}
+
+impl Default for MatrixConfig {
+    fn default() -> Self {
+        Self {
+            iterations: 1000,
+            zk_iterations: 20,
+            msg_size: 1024,
+            zk_backend: ZkBackend::Groth16,
+            transport: TransportConfig::default(),
+        }
+    }
+}
+
+/// Runs every transport plain and proof-carrying. Runs that fail are reported
+/// on stderr and left out of the result.
+pub async fn run_matrix(config: &MatrixConfig) -> Result<Vec<BenchReport>> {
+    let prover = config.zk_backend.prover(config.msg_size)?;
+
+    let mut reports = Vec::new();
+    run_transport::<WebRtcTransport>(config, &prover, &mut reports).await;
+    run_transport::<UdpTransport>(config, &prover, &mut reports).await;
+    run_transport::<ReliableUdpTransport>(config, &prover, &mut reports).await;
+    run_transport::<Http2Transport>(config, &prover, &mut reports).await;
+    run_transport::<WebSocketTransport>(config, &prover, &mut reports).await;
+
+    Ok(reports)
+}
+
+async fn run_transport<T: Transport>(
+    config: &MatrixConfig,
+    prover: &Arc<dyn ProofSystem>,
+    reports: &mut Vec<BenchReport>,
+) {
+    match ping_pong::<T>(&config.transport, config.iterations, config.msg_size).await {
+        Ok(result) => reports.push(BenchReport::new(T::NAME, None, config.msg_size, config.iterations, &result)),
+        Err(e) => eprintln!("Error occurred during {} ping pong: {:?}", T::NAME, e),
+    }
+
+    match zk_ping_pong::<T>(&config.transport, config.zk_iterations, config.msg_size, prover.clone()).await {
+        Ok(result) => reports.push(BenchReport::new(
+            T::NAME,
+            Some(config.zk_backend),
+            config.msg_size,
+            config.zk_iterations,
+            &result,
+        )),
+        Err(e) => eprintln!(
+            "Error occurred during {} + {} ping pong: {:?}",
+            T::NAME,
+            config.zk_backend.name(),
+            e
+        ),
+    }
+}
+
+/// Renders the readme comparison table. Latency is the median round trip;
+/// cells without a matching report are left empty.
+pub fn markdown_table(reports: &[BenchReport]) -> String {
+    let mut table = String::from(
+        "| | Normal Latency (ms) | Normal Throughput (MB/s) | ZK Latency (ms) | ZK Throughput (MB/s) |\n\
+         | -- | -- | -- | -- | -- |\n",
+    );
+
+    for transport in MATRIX_TRANSPORTS {
+        let find = |zk: bool| {
+            reports
+                .iter()
+                .find(|r| r.transport == transport && r.zk_backend.is_some() == zk)
+        };
+        let cells = |report: Option<&BenchReport>| match report {
+            Some(r) => (
+                format!("{:.3}", r.latency.p50.as_secs_f64() * 1_000.0),
+                format!("{:.3}", r.throughput_mbps),
+            ),
+            None => (String::new(), String::new()),
+        };
+        let (normal_latency, normal_throughput) = cells(find(false));
+        let (zk_latency, zk_throughput) = cells(find(true));
+
+        table.push_str(&format!(
+            "| {} | {} | {} | {} | {} |\n",
+            transport, normal_latency, normal_throughput, zk_latency, zk_throughput
+        ));
+    }
+
+    table
+}
+
+/// Marks where the generated table starts in the readme.
+pub const TABLE_START: &str = "<!-- benchmark-table:start -->";
+/// Marks where the generated table ends in the readme.
+pub const TABLE_END: &str = "<!-- benchmark-table:end -->";
+
+/// Replaces everything between the [`TABLE_START`] and [`TABLE_END`] lines of
+/// `readme` with `table`, leaving the rest untouched.
+pub fn replace_readme_table(readme: &str, table: &str) -> Result<String> {
+    let lines: Vec<&str> = readme.lines().collect();
+    let marker = |marker: &str| {
+        lines
+            .iter()
+            .position(|line| line.trim() == marker)
+            .ok_or_else(|| anyhow!("No {} line found in readme", marker))
+    };
+    let start = marker(TABLE_START)?;
+    let end = marker(TABLE_END)?;
+    if end < start {
+        return Err(anyhow!("{} comes before {} in readme", TABLE_END, TABLE_START));
+    }
+
+    let mut out = String::new();
+    for line in &lines[..=start] {
+        out.push_str(line);
+        out.push('\n');
+    }
+    out.push_str(table);
+    for line in &lines[end..] {
+        out.push_str(line);
+        out.push('\n');
+    }
+    Ok(out)
+}
+
+#[test]
+fn test_replace_readme_table() -> Result<()> {
+    let readme = format!(
+        "Intro\n\n| other |\n| -- |\n\n{}\n| | a |\n| -- | -- |\n| UDP |   |\n{}\n\nOutro\n",
+        TABLE_START, TABLE_END
+    );
+    let table = markdown_table(&[]);
+    let updated = replace_readme_table(&readme, &table)?;
+
+    // Only the table between the markers changes
+    assert!(updated.starts_with(&format!("Intro\n\n| other |\n| -- |\n\n{}\n| | Normal Latency (ms)", TABLE_START)));
+    assert!(updated.ends_with(&format!("| WebSocket |  |  |  |  |\n{}\n\nOutro\n", TABLE_END)));
+    assert_eq!(replace_readme_table(&updated, &table)?, updated);
+    assert!(replace_readme_table("| | a |\n", &table).is_err());
+
+    Ok(())
+}
@@ -0,0 +1,365 @@
+use anyhow::{anyhow, Result};
+use async_trait::async_trait;
+use std::collections::BTreeMap;
+use std::io::ErrorKind;
+use std::net::SocketAddr;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use tokio::sync::mpsc;
+use tokio::task::JoinHandle;
+
+use crate::impairment::ImpairedUdpSocket;
+use crate::transport::{Transport, TransportConfig};
+use crate::udp_ping_pong::{bind_udp, MAX_DATAGRAM_SIZE};
+
+/// Settings for [`ReliableUdpTransport`]. Both ends use the same ones.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct ReliableUdpConfig {
+    /// How far ahead of the oldest unacknowledged message the sender may get;
+    /// sending blocks beyond that.
+    pub window: usize,
+    /// Retransmission timeout until the first round trip has been measured.
+    pub initial_rto: Duration,
+    /// Lower bound for the retransmission timeout.
+    pub min_rto: Duration,
+    /// Upper bound for the retransmission timeout as it backs off.
+    pub max_rto: Duration,
+}
+
+impl Default for ReliableUdpConfig {
+    fn default() -> Self {
+        Self {
+            window: 64,
+            initial_rto: Duration::from_millis(200),
+            min_rto: Duration::from_millis(5),
+            max_rto: Duration::from_secs(2),
+        }
+    }
+}
+
+/// How long closing waits for messages still in flight to be acknowledged.
+const LINGER: Duration = Duration::from_secs(1);
+
+const DATA: u8 = 0;
+const ACK: u8 = 1;
+/// Type byte and sequence number in front of every payload.
+const DATA_HEADER_LEN: usize = 9;
+
+/// A small reliable, ordered datagram protocol over UDP, one datagram per message.
+///
+/// Every message gets a sequence number and stays buffered until the peer
+/// acknowledges it. Each ACK names the message it answers and the next one the
+/// peer expects, so a lost ACK is covered by any later one. Unacknowledged
+/// messages are resent once the retransmission timeout, estimated from measured
+/// round trips as in RFC 6298, runs out, and the timeout doubles on every
+/// resend. The receiver holds back out-of-order messages until the gap is filled.
+///
+/// Loss, delay and so on come from `config.udp.impairment`, like [`crate::UdpTransport`].
+pub struct ReliableUdpTransport {
+    outgoing: mpsc::Sender<Vec<u8>>,
+    incoming: mpsc::UnboundedReceiver<Vec<u8>>,
+    endpoint: JoinHandle<Result<()>>,
+    local_addr: SocketAddr,
+    retransmissions: Arc<AtomicU64>,
+}
+
+impl ReliableUdpTransport {
+    fn start(socket: ImpairedUdpSocket, config: ReliableUdpConfig) -> Result<Self> {
+        let local_addr = socket.local_addr()?;
+        let (outgoing, outgoing_rx) = mpsc::channel(1);
+        let (incoming_tx, incoming) = mpsc::unbounded_channel();
+        let retransmissions = Arc::new(AtomicU64::new(0));
+
+        let endpoint = Endpoint {
+            socket,
+            config,
+            next_seq: 0,
+            unacked: BTreeMap::new(),
+            rto: RtoEstimator::new(&config),
+            next_expected: 0,
+            out_of_order: BTreeMap::new(),
+            incoming: incoming_tx,
+            retransmissions: retransmissions.clone(),
+        };
+        let endpoint = tokio::spawn(endpoint.run(outgoing_rx));
+
+        Ok(Self { outgoing, incoming, endpoint, local_addr, retransmissions })
+    }
+
+    /// Messages this end has sent more than once so far.
+    pub fn retransmissions(&self) -> u64 {
+        self.retransmissions.load(Ordering::Relaxed)
+    }
+}
+
+#[async_trait]
+impl Transport for ReliableUdpTransport {
+    const NAME: &'static str = "Reliable UDP";
+
+    async fn connect(config: &TransportConfig) -> Result<(Self, Self)> {
+        if config.reliable_udp.window == 0 {
+            return Err(anyhow!("send window must be at least 1"));
+        }
+        let impairment = config.udp.impairment;
+        let socket1 = ImpairedUdpSocket::new(bind_udp(config.client_addr).await?, impairment)?;
+        let socket2 = ImpairedUdpSocket::new(bind_udp(config.server_addr).await?, impairment.reversed())?;
+
+        socket1.connect(socket2.local_addr()?).await?;
+        socket2.connect(socket1.local_addr()?).await?;
+
+        Ok((
+            Self::start(socket1, config.reliable_udp)?,
+            Self::start(socket2, config.reliable_udp)?,
+        ))
+    }
+
+    async fn send(&mut self, msg: &[u8]) -> Result<()> {
+        if msg.len() + DATA_HEADER_LEN > MAX_DATAGRAM_SIZE {
+            return Err(anyhow!("message of {} bytes does not fit in a UDP datagram", msg.len()));
+        }
+        self.outgoing
+            .send(msg.to_vec())
+            .await
+            .map_err(|_| anyhow!("Reliable UDP connection closed"))
+    }
+
+    async fn recv(&mut self) -> Result<Vec<u8>> {
+        self.incoming.recv().await.ok_or_else(|| anyhow!("Reliable UDP connection closed"))
+    }
+
+    async fn close(self) -> Result<()> {
+        drop(self.outgoing);
+        self.endpoint.await?
+    }
+
+    fn local_addr(&self) -> Option<SocketAddr> {
+        Some(self.local_addr)
+    }
+}
+
+// A message sent but not acknowledged yet
+struct InFlight {
+    datagram: Vec<u8>,
+    sent: Instant,
+    /// Round trips of resent messages are ambiguous, so they aren't measured.
+    retransmitted: bool,
+}
+
+// Owns the socket and runs both directions of the protocol
+struct Endpoint {
+    socket: ImpairedUdpSocket,
+    config: ReliableUdpConfig,
+    next_seq: u64,
+    unacked: BTreeMap<u64, InFlight>,
+    rto: RtoEstimator,
+    next_expected: u64,
+    out_of_order: BTreeMap<u64, Vec<u8>>,
+    incoming: mpsc::UnboundedSender<Vec<u8>>,
+    retransmissions: Arc<AtomicU64>,
+}
+
+impl Endpoint {
+    async fn run(mut self, mut outgoing: mpsc::Receiver<Vec<u8>>) -> Result<()> {
+        let mut buf = vec![0; MAX_DATAGRAM_SIZE];
+        // Set once the handle is closed; keeps going until everything is acknowledged
+        let mut linger_until = None;
+
+        loop {
+            if linger_until.is_some_and(|until| self.unacked.is_empty() || Instant::now() >= until) {
+                return Ok(());
+            }
+
+            let retransmit_at = self.unacked.values().map(|msg| msg.sent + self.rto.current).min();
+            let wake_at = match (retransmit_at, linger_until) {
+                (Some(at), Some(until)) => Some(at.min(until)),
+                (at, until) => at.or(until),
+            };
+
+            tokio::select! {
+                msg = outgoing.recv(), if linger_until.is_none() && self.window_open() => {
+                    match msg {
+                        Some(msg) => self.send_data(&msg).await?,
+                        None => linger_until = Some(Instant::now() + LINGER),
+                    }
+                }
+                received = self.socket.recv(&mut buf) => match received {
+                    Ok(len) => self.handle(&buf[..len]).await?,
+                    // The peer's socket is gone, e.g. it closed first; its
+                    // retransmissions will stop too
+                    Err(e) if e.kind() == ErrorKind::ConnectionRefused => {}
+                    Err(e) => return Err(e.into()),
+                },
+                _ = sleep_until(wake_at) => self.retransmit().await?,
+            }
+        }
+    }
+
+    // Whether the next message stays within `window` of the oldest unacknowledged
+    // one, so the peer has room to hold it until the gap before it is filled
+    fn window_open(&self) -> bool {
+        let oldest = self.unacked.keys().next().copied().unwrap_or(self.next_seq);
+        self.next_seq - oldest < self.config.window as u64
+    }
+
+    async fn send_data(&mut self, msg: &[u8]) -> Result<()> {
+        let seq = self.next_seq;
+        self.next_seq += 1;
+
+        let mut datagram = Vec::with_capacity(DATA_HEADER_LEN + msg.len());
+        datagram.push(DATA);
+        datagram.extend_from_slice(&seq.to_be_bytes());
+        datagram.extend_from_slice(msg);
+        self.socket.send(&datagram).await?;
+
+        self.unacked.insert(seq, InFlight { datagram, sent: Instant::now(), retransmitted: false });
+        Ok(())
+    }
+
+    async fn handle(&mut self, datagram: &[u8]) -> Result<()> {
+        match datagram.split_first() {
+            Some((&DATA, rest)) if rest.len() >= 8 => {
+                let seq = read_u64(rest);
+                if seq >= self.next_expected + self.config.window as u64 {
+                    // Beyond the window; the sender will try again
+                    return Ok(());
+                }
+                if seq >= self.next_expected {
+                    self.out_of_order.insert(seq, rest[8..].to_vec());
+                    while let Some(msg) = self.out_of_order.remove(&self.next_expected) {
+                        let _ = self.incoming.send(msg);
+                        self.next_expected += 1;
+                    }
+                }
+
+                // Acknowledge duplicates too, in case the first ACK was lost
+                let mut ack = [0; 17];
+                ack[0] = ACK;
+                ack[1..9].copy_from_slice(&self.next_expected.to_be_bytes());
+                ack[9..].copy_from_slice(&seq.to_be_bytes());
+                self.socket.send(&ack).await?;
+            }
+            Some((&ACK, rest)) if rest.len() >= 16 => {
+                let cumulative = read_u64(rest);
+                let seq = read_u64(&rest[8..]);
+                if let Some(msg) = self.unacked.remove(&seq) {
+                    if !msg.retransmitted {
+                        self.rto.sample(msg.sent.elapsed());
+                    }
+                }
+                self.unacked = self.unacked.split_off(&cumulative);
+            }
+            _ => return Err(anyhow!("Malformed reliable UDP datagram of {} bytes", datagram.len())),
+        }
+        Ok(())
+    }
+
+    // Resends every message whose timeout ran out, then backs the timeout off
+    async fn retransmit(&mut self) -> Result<()> {
+        let now = Instant::now();
+        let mut resent = 0;
+        for msg in self.unacked.values_mut() {
+            if msg.sent + self.rto.current <= now {
+                self.socket.send(&msg.datagram).await?;
+                msg.sent = now;
+                msg.retransmitted = true;
+                resent += 1;
+            }
+        }
+        if resent > 0 {
+            self.retransmissions.fetch_add(resent, Ordering::Relaxed);
+            self.rto.back_off();
+        }
+        Ok(())
+    }
+}
+
+// Retransmission timeout from smoothed round trip time and its variation (RFC 6298)
+struct RtoEstimator {
+    srtt: Option<Duration>,
+    rttvar: Duration,
+    current: Duration,
+    min: Duration,
+    max: Duration,
+}
+
+impl RtoEstimator {
+    fn new(config: &ReliableUdpConfig) -> Self {
+        Self {
+            srtt: None,
+            rttvar: Duration::ZERO,
+            current: config.initial_rto,
+            min: config.min_rto,
+            max: config.max_rto,
+        }
+    }
+
+    fn sample(&mut self, rtt: Duration) {
+        let srtt = match self.srtt {
+            None => {
+                self.rttvar = rtt / 2;
+                rtt
+            }
+            Some(srtt) => {
+                self.rttvar = (self.rttvar * 3 + srtt.abs_diff(rtt)) / 4;
+                (srtt * 7 + rtt) / 8
+            }
+        };
+        self.srtt = Some(srtt);
+        self.current = (srtt + self.rttvar * 4).clamp(self.min, self.max);
+    }
+
+    fn back_off(&mut self) {
+        self.current = (self.current * 2).min(self.max);
+    }
+}
+
+fn read_u64(bytes: &[u8]) -> u64 {
+    u64::from_be_bytes(bytes[..8].try_into().unwrap())
+}
+
+// Sleeps until `deadline`, or forever if there is none
+async fn sleep_until(deadline: Option<Instant>) {
+    match deadline {
+        Some(deadline) => tokio::time::sleep_until(deadline.into()).await,
+        None => std::future::pending().await,
+    }
+}
+
+#[tokio::test]
+async fn test_reliable_udp_under_loss() -> Result<()> {
+    use crate::impairment::NetworkImpairment;
+    use crate::transport::ping_pong;
+    use crate::udp_ping_pong::UdpConfig;
+
+    // A fifth of all datagrams dropped and the rest jittered out of order
+    let impairment = NetworkImpairment {
+        loss: 0.2,
+        latency: Duration::from_millis(1),
+        jitter: Duration::from_millis(2),
+        seed: Some(3),
+        ..Default::default()
+    };
+    let config = TransportConfig { udp: UdpConfig { impairment, ..Default::default() }, ..Default::default() };
+
+    // A burst larger than the window still arrives complete and in order
+    let (mut client, mut server) = ReliableUdpTransport::connect(&config).await?;
+    for i in 0..200u32 {
+        client.send(&i.to_be_bytes()).await?;
+    }
+    for i in 0..200u32 {
+        assert_eq!(server.recv().await?, i.to_be_bytes());
+    }
+    assert!(client.retransmissions() > 0);
+    client.close().await?;
+    server.close().await?;
+
+    let result = ping_pong::<ReliableUdpTransport>(&config, 20, 64).await?;
+    assert_eq!(result.total_bytes, 20 * 64 * 2);
+
+    // Nothing could ever be sent through an empty window
+    let reliable_udp = ReliableUdpConfig { window: 0, ..Default::default() };
+    assert!(ReliableUdpTransport::connect(&TransportConfig { reliable_udp, ..config }).await.is_err());
+
+    Ok(())
+}
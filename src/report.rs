@@ -0,0 +1,302 @@
+use anyhow::{anyhow, Result};
+use serde::{Deserialize, Serialize};
+use std::io::Write;
+use std::net::SocketAddr;
+use std::str::FromStr;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use crate::stats::{duration_ms, BenchResult, Delivery, Fairness, LatencyStats, ProofPhases, SetupTimings};
+use crate::zk_transport::ZkBackend;
+
+/// The machine a report was produced on.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct MachineInfo {
+    pub os: String,
+    pub arch: String,
+    pub cpus: usize,
+    pub crate_version: String,
+}
+
+impl MachineInfo {
+    pub fn current() -> Self {
+        Self {
+            os: std::env::consts::OS.to_owned(),
+            arch: std::env::consts::ARCH.to_owned(),
+            cpus: std::thread::available_parallelism().map_or(1, |n| n.get()),
+            crate_version: env!("CARGO_PKG_VERSION").to_owned(),
+        }
+    }
+}
+
+/// A single benchmark run, in a form that can be archived and diffed.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct BenchReport {
+    pub transport: String,
+    /// `None` for plain runs.
+    pub zk_backend: Option<String>,
+    pub msg_size: usize,
+    pub iterations: u64,
+    #[serde(with = "duration_ms")]
+    pub elapsed: Duration,
+    pub total_bytes: usize,
+    pub throughput_mbps: f64,
+    pub latency: LatencyStats,
+    /// Address the server end was bound to, when the transport has one.
+    #[serde(default)]
+    pub server_addr: Option<SocketAddr>,
+    /// Messages sent and delivered, for transports that can drop messages.
+    #[serde(default)]
+    pub delivery: Option<Delivery>,
+    /// Connection setup phases, when the run connected from scratch.
+    #[serde(default)]
+    pub setup: Option<SetupTimings>,
+    /// Per-flow throughput spread, for runs with concurrent flows.
+    #[serde(default)]
+    pub fairness: Option<Fairness>,
+    /// Where proving and verifying spent their time, for proof-only runs.
+    #[serde(default)]
+    pub phases: Option<ProofPhases>,
+    pub machine: MachineInfo,
+    /// Seconds since the Unix epoch when the report was created.
+    pub timestamp: u64,
+}
+
+impl BenchReport {
+    pub fn new(
+        transport: &str,
+        zk_backend: Option<ZkBackend>,
+        msg_size: usize,
+        iterations: u64,
+        result: &BenchResult,
+    ) -> Self {
+        Self {
+            transport: transport.to_owned(),
+            zk_backend: zk_backend.map(|backend| backend.name().to_owned()),
+            msg_size,
+            iterations,
+            elapsed: result.elapsed,
+            total_bytes: result.total_bytes,
+            throughput_mbps: result.throughput_mbps(),
+            latency: result.latency,
+            server_addr: result.server_addr,
+            delivery: result.delivery,
+            setup: result.setup.clone(),
+            fairness: result.fairness,
+            phases: result.phases,
+            machine: MachineInfo::current(),
+            timestamp: SystemTime::now().duration_since(UNIX_EPOCH).map_or(0, |d| d.as_secs()),
+        }
+    }
+}
+
+/// Output formats for a set of reports.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ReportFormat {
+    /// One human-readable line per report.
+    Text,
+    Json,
+    Csv,
+}
+
+impl ReportFormat {
+    pub fn write<W: Write>(&self, reports: &[BenchReport], writer: W) -> Result<()> {
+        match self {
+            ReportFormat::Text => write_text(reports, writer),
+            ReportFormat::Json => write_json(reports, writer),
+            ReportFormat::Csv => write_csv(reports, writer),
+        }
+    }
+}
+
+impl FromStr for ReportFormat {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s.to_ascii_lowercase().as_str() {
+            "text" => Ok(ReportFormat::Text),
+            "json" => Ok(ReportFormat::Json),
+            "csv" => Ok(ReportFormat::Csv),
+            other => Err(anyhow!("Unknown report format: {}", other)),
+        }
+    }
+}
+
+/// Writes one line per report, in the same shape the benches print.
+pub fn write_text<W: Write>(reports: &[BenchReport], mut writer: W) -> Result<()> {
+    for report in reports {
+        let name = match &report.zk_backend {
+            Some(backend) => format!("{} + {}", report.transport, backend),
+            None => report.transport.clone(),
+        };
+        write!(
+            writer,
+            "{}: Latency: {}, Throughput: {:.2} MB/s",
+            name, report.latency, report.throughput_mbps
+        )?;
+        if let Some(delivery) = report.delivery {
+            write!(
+                writer,
+                ", Delivered: {}/{} ({:.1}%)",
+                delivery.delivered,
+                delivery.sent,
+                delivery.ratio() * 100.0
+            )?;
+        }
+        if let Some(setup) = &report.setup {
+            write!(writer, ", Setup: {}", setup)?;
+        }
+        if let Some(fairness) = report.fairness {
+            write!(writer, ", Fairness: {}", fairness)?;
+        }
+        if let Some(phases) = report.phases {
+            write!(writer, ", Phases: {}", phases)?;
+        }
+        match report.server_addr {
+            Some(addr) => writeln!(writer, " (server on {})", addr)?,
+            None => writeln!(writer)?,
+        }
+    }
+    Ok(())
+}
+
+/// Writes `reports` as a pretty-printed JSON array.
+pub fn write_json<W: Write>(reports: &[BenchReport], mut writer: W) -> Result<()> {
+    serde_json::to_writer_pretty(&mut writer, reports)?;
+    writeln!(writer)?;
+    Ok(())
+}
+
+/// Writes `reports` as CSV, one row per report. Durations are in milliseconds.
+pub fn write_csv<W: Write>(reports: &[BenchReport], mut writer: W) -> Result<()> {
+    writeln!(
+        writer,
+        "transport,zk_backend,msg_size,iterations,elapsed_ms,total_bytes,throughput_mbps,\
+         min_ms,p50_ms,p90_ms,p99_ms,p999_ms,max_ms,mean_ms,jitter_ms,server_addr,\
+         sent,delivered,delivered_ratio,setup_ms,setup_phases,time_to_first_byte_ms,\
+         flows,min_flow_mbps,max_flow_mbps,jain_index,proof_setup_ms,witness_ms,prove_ms,\
+         serialize_ms,verify_ms,proof_bytes,os,arch,cpus,crate_version,timestamp"
+    )?;
+
+    let ms = |d: Duration| d.as_secs_f64() * 1_000.0;
+    for report in reports {
+        let latency = &report.latency;
+        writeln!(
+            writer,
+            "{},{},{},{},{},{},{},{},{},{},{},{},{},{},{},{},{},{},{},{},{},{},{},{},{},{},{},{},{},{},{},{},{},{},{},{},{}",
+            csv_field(&report.transport),
+            csv_field(report.zk_backend.as_deref().unwrap_or("")),
+            report.msg_size,
+            report.iterations,
+            ms(report.elapsed),
+            report.total_bytes,
+            report.throughput_mbps,
+            ms(latency.min),
+            ms(latency.p50),
+            ms(latency.p90),
+            ms(latency.p99),
+            ms(latency.p999),
+            ms(latency.max),
+            ms(latency.mean),
+            ms(latency.jitter),
+            report.server_addr.map(|addr| csv_field(&addr.to_string())).unwrap_or_default(),
+            report.delivery.map(|d| d.sent.to_string()).unwrap_or_default(),
+            report.delivery.map(|d| d.delivered.to_string()).unwrap_or_default(),
+            report.delivery.map(|d| d.ratio().to_string()).unwrap_or_default(),
+            report.setup.as_ref().map(|setup| ms(setup.total()).to_string()).unwrap_or_default(),
+            report.setup.as_ref().map(|setup| csv_field(&setup_phases(setup))).unwrap_or_default(),
+            report
+                .setup
+                .as_ref()
+                .and_then(|setup| setup.time_to_first_byte)
+                .map(|ttfb| ms(ttfb).to_string())
+                .unwrap_or_default(),
+            report.fairness.map(|f| f.flows.to_string()).unwrap_or_default(),
+            report.fairness.map(|f| f.min_mbps.to_string()).unwrap_or_default(),
+            report.fairness.map(|f| f.max_mbps.to_string()).unwrap_or_default(),
+            report.fairness.map(|f| f.jain_index.to_string()).unwrap_or_default(),
+            report.phases.map(|p| ms(p.setup).to_string()).unwrap_or_default(),
+            report.phases.map(|p| ms(p.witness).to_string()).unwrap_or_default(),
+            report.phases.map(|p| ms(p.prove).to_string()).unwrap_or_default(),
+            report.phases.map(|p| ms(p.serialize).to_string()).unwrap_or_default(),
+            report.phases.map(|p| ms(p.verify).to_string()).unwrap_or_default(),
+            report.phases.map(|p| p.proof_size.to_string()).unwrap_or_default(),
+            csv_field(&report.machine.os),
+            csv_field(&report.machine.arch),
+            report.machine.cpus,
+            csv_field(&report.machine.crate_version),
+            report.timestamp,
+        )?;
+    }
+    Ok(())
+}
+
+// Renders setup phases as `name=ms;name=ms`
+fn setup_phases(setup: &SetupTimings) -> String {
+    let phases: Vec<_> = setup
+        .phases
+        .iter()
+        .map(|phase| format!("{}={}", phase.name, phase.elapsed.as_secs_f64() * 1_000.0))
+        .collect();
+    phases.join(";")
+}
+
+// Quotes a field if it contains a delimiter, quote or newline
+fn csv_field(value: &str) -> String {
+    if value.contains([',', '"', '\n']) {
+        format!("\"{}\"", value.replace('"', "\"\""))
+    } else {
+        value.to_owned()
+    }
+}
+
+#[test]
+fn test_report_writers() -> Result<()> {
+    use crate::stats::LatencyHistogram;
+
+    let mut histogram = LatencyHistogram::new();
+    histogram.record(Duration::from_micros(1500));
+    histogram.record(Duration::from_micros(2500));
+    let result = BenchResult::new(Duration::from_millis(4), 4096, &histogram);
+    let mut setup = SetupTimings::new();
+    setup.push("tcp_connect", Duration::from_millis(1));
+    setup.push("ws_upgrade", Duration::from_millis(2));
+    setup.time_to_first_byte = Some(Duration::from_millis(4));
+    let phases = ProofPhases {
+        setup: Duration::from_millis(5),
+        witness: Duration::from_millis(1),
+        prove: Duration::from_millis(2),
+        serialize: Duration::from_millis(0),
+        verify: Duration::from_millis(3),
+        proof_size: 192,
+    };
+    let lossy = result.clone().with_delivery(2, 1).with_setup(setup).with_fairness(Fairness::new(&[1.0, 3.0]));
+    let reports = vec![
+        BenchReport::new("UDP", None, 1024, 2, &lossy),
+        BenchReport::new("WebSocket", Some(ZkBackend::Groth16), 1024, 2, &result),
+        BenchReport::new("none", Some(ZkBackend::Plonky2), 1024, 2, &result.clone().with_phases(phases)),
+    ];
+
+    let mut json = Vec::new();
+    ReportFormat::Json.write(&reports, &mut json)?;
+    let parsed: Vec<BenchReport> = serde_json::from_slice(&json)?;
+    assert_eq!(parsed.len(), 3);
+    assert_eq!(parsed[1].zk_backend.as_deref(), Some("Groth16"));
+    assert_eq!(parsed[0].latency, reports[0].latency);
+    assert_eq!(parsed[0].delivery.map(|d| d.ratio()), Some(0.5));
+    assert_eq!(parsed[0].setup, reports[0].setup);
+    assert_eq!(parsed[1].setup, None);
+    assert_eq!(parsed[0].fairness.map(|f| f.jain_index), Some(0.8));
+    assert_eq!(parsed[2].phases, Some(phases));
+
+    let mut csv = Vec::new();
+    ReportFormat::Csv.write(&reports, &mut csv)?;
+    let csv = String::from_utf8(csv)?;
+    let lines: Vec<_> = csv.lines().collect();
+    assert_eq!(lines.len(), 4);
+    assert!(lines[1].starts_with("UDP,,1024,2,4,4096,"));
+    assert_eq!(lines[0].split(',').count(), lines[2].split(',').count());
+    assert!(lines[1].contains(",2,1,0.5,3,tcp_connect=1;ws_upgrade=2,4,2,1,3,0.8,,,,,,,"));
+    assert!(lines[3].contains(",5,1,2,0,3,192,"));
+
+    Ok(())
+}
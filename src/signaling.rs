@@ -0,0 +1,250 @@
+use anyhow::{anyhow, Context, Result};
+use async_trait::async_trait;
+use futures_util::{SinkExt, StreamExt};
+use serde::{Deserialize, Serialize};
+use std::net::SocketAddr;
+use tokio::io::{AsyncBufRead, AsyncBufReadExt, AsyncWrite, AsyncWriteExt, BufReader, Lines, Stderr, Stdin};
+use tokio::net::TcpStream;
+use tokio::sync::mpsc;
+use tokio::task::JoinHandle;
+use tokio_tungstenite::{accept_async, connect_async, tungstenite::Message, MaybeTlsStream, WebSocketStream};
+use webrtc::ice_transport::ice_candidate::RTCIceCandidateInit;
+use webrtc::peer_connection::sdp::session_description::RTCSessionDescription;
+
+use crate::transport::bind_tcp;
+
+/// A message exchanged between two WebRTC peers while they negotiate.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum SignalMessage {
+    /// An offer or answer. Boxed since SDP dwarfs the other variants.
+    Description { description: Box<RTCSessionDescription> },
+    /// A local candidate, sent as soon as it is gathered when trickling.
+    Candidate { candidate: RTCIceCandidateInit },
+    /// The sender has gathered all of its candidates.
+    EndOfCandidates,
+}
+
+/// Carries [`SignalMessage`]s to the remote peer and back.
+///
+/// Each end of a negotiation owns one `Signaling`, so the two peers can live in
+/// the same process, in separate processes, or on separate hosts.
+#[async_trait]
+pub trait Signaling: Send {
+    async fn send(&mut self, msg: &SignalMessage) -> Result<()>;
+
+    async fn recv(&mut self) -> Result<SignalMessage>;
+}
+
+/// Both ends of a signaling channel within one process.
+pub struct InProcessSignaling {
+    tx: mpsc::UnboundedSender<SignalMessage>,
+    rx: mpsc::UnboundedReceiver<SignalMessage>,
+}
+
+impl InProcessSignaling {
+    pub fn pair() -> (Self, Self) {
+        let (tx1, rx1) = mpsc::unbounded_channel();
+        let (tx2, rx2) = mpsc::unbounded_channel();
+        (Self { tx: tx1, rx: rx2 }, Self { tx: tx2, rx: rx1 })
+    }
+}
+
+#[async_trait]
+impl Signaling for InProcessSignaling {
+    async fn send(&mut self, msg: &SignalMessage) -> Result<()> {
+        self.tx.send(msg.clone()).map_err(|_| anyhow!("Remote peer hung up"))
+    }
+
+    async fn recv(&mut self) -> Result<SignalMessage> {
+        self.rx.recv().await.ok_or_else(|| anyhow!("Remote peer hung up"))
+    }
+}
+
+/// A client of a [`SignalingServer`], sending one JSON text frame per message.
+pub struct WebSocketSignaling {
+    stream: WebSocketStream<MaybeTlsStream<TcpStream>>,
+}
+
+impl WebSocketSignaling {
+    /// Joins the signaling server at `url`, e.g. `ws://host:port`.
+    pub async fn connect(url: &str) -> Result<Self> {
+        let (stream, _) = connect_async(url)
+            .await
+            .with_context(|| format!("Failed to reach signaling server at {}", url))?;
+        Ok(Self { stream })
+    }
+}
+
+#[async_trait]
+impl Signaling for WebSocketSignaling {
+    async fn send(&mut self, msg: &SignalMessage) -> Result<()> {
+        self.stream.send(Message::Text(serde_json::to_string(msg)?)).await?;
+        Ok(())
+    }
+
+    async fn recv(&mut self) -> Result<SignalMessage> {
+        loop {
+            match self.stream.next().await {
+                Some(Ok(Message::Text(text))) => return Ok(serde_json::from_str(&text)?),
+                Some(Ok(Message::Close(_))) | None => return Err(anyhow!("Signaling server closed the connection")),
+                Some(Ok(_)) => {}
+                Some(Err(e)) => return Err(e.into()),
+            }
+        }
+    }
+}
+
+/// Pairs up WebSocket clients in the order they connect and relays every text
+/// frame from one client of a pair to the other.
+pub struct SignalingServer {
+    addr: SocketAddr,
+    task: JoinHandle<()>,
+}
+
+impl SignalingServer {
+    pub async fn bind(addr: SocketAddr) -> Result<Self> {
+        let listener = bind_tcp(addr).await?;
+        let addr = listener.local_addr()?;
+
+        let task = tokio::spawn(async move {
+            loop {
+                // Finish each handshake as soon as the client arrives, so the first
+                // one isn't left waiting for a peer to show up
+                let pair = async {
+                    let (first, _) = listener.accept().await?;
+                    let first = accept_async(first).await?;
+                    let (second, _) = listener.accept().await?;
+                    let second = accept_async(second).await?;
+                    Result::<_>::Ok((first, second))
+                };
+                match pair.await {
+                    Ok((first, second)) => {
+                        tokio::spawn(async move {
+                            if let Err(e) = relay(first, second).await {
+                                eprintln!("Signaling relay error: {:?}", e);
+                            }
+                        });
+                    }
+                    Err(e) => eprintln!("Signaling server accept error: {:?}", e),
+                }
+            }
+        });
+
+        Ok(Self { addr, task })
+    }
+
+    pub fn addr(&self) -> SocketAddr {
+        self.addr
+    }
+
+    pub fn close(&self) {
+        self.task.abort();
+    }
+}
+
+// Forwards text frames both ways until either side goes away. Peers often exit
+// without a closing handshake once connected, so read errors just end the relay.
+async fn relay(first: WebSocketStream<TcpStream>, second: WebSocketStream<TcpStream>) -> Result<()> {
+    let (mut first_tx, mut first_rx) = first.split();
+    let (mut second_tx, mut second_rx) = second.split();
+
+    loop {
+        let (msg, tx) = tokio::select! {
+            msg = first_rx.next() => (msg, &mut second_tx),
+            msg = second_rx.next() => (msg, &mut first_tx),
+        };
+        match msg {
+            Some(Ok(msg @ Message::Text(_))) => tx.send(msg).await?,
+            Some(Ok(Message::Close(_)) | Err(_)) | None => break,
+            Some(Ok(_)) => {}
+        }
+    }
+
+    let _ = first_tx.close().await;
+    let _ = second_tx.close().await;
+    Ok(())
+}
+
+/// Writes each outgoing message as one line of JSON and reads incoming messages
+/// one line at a time, e.g. for copying and pasting between two terminals.
+/// Trickle ICE sends a line per candidate, so pasting works best with full gathering.
+pub struct LineSignaling<R, W> {
+    lines: Lines<R>,
+    writer: W,
+}
+
+impl LineSignaling<BufReader<Stdin>, Stderr> {
+    /// Messages for the remote peer go to stderr, leaving stdout to the report;
+    /// its replies are read from stdin.
+    pub fn stdio() -> Self {
+        Self::new(BufReader::new(tokio::io::stdin()), tokio::io::stderr())
+    }
+}
+
+impl<R: AsyncBufRead + Unpin, W: AsyncWrite + Unpin> LineSignaling<R, W> {
+    pub fn new(reader: R, writer: W) -> Self {
+        Self { lines: reader.lines(), writer }
+    }
+}
+
+#[async_trait]
+impl<R, W> Signaling for LineSignaling<R, W>
+where
+    R: AsyncBufRead + Send + Unpin,
+    W: AsyncWrite + Send + Unpin,
+{
+    async fn send(&mut self, msg: &SignalMessage) -> Result<()> {
+        let mut line = serde_json::to_string(msg)?;
+        line.push('\n');
+        self.writer.write_all(line.as_bytes()).await?;
+        self.writer.flush().await?;
+        Ok(())
+    }
+
+    async fn recv(&mut self) -> Result<SignalMessage> {
+        loop {
+            let line = self.lines.next_line().await?.ok_or_else(|| anyhow!("Signaling input closed"))?;
+            // Skip blank lines left over from pasting
+            if !line.trim().is_empty() {
+                return Ok(serde_json::from_str(line.trim())?);
+            }
+        }
+    }
+}
+
+#[tokio::test]
+async fn test_signaling_between_separate_peers() -> Result<()> {
+    use crate::webrtc_benchmark::{webrtc_echo_peer, webrtc_remote_benchmark, IceGathering, WebRtcConfig};
+
+    let server = SignalingServer::bind("127.0.0.1:0".parse()?).await?;
+    let url = format!("ws://{}", server.addr());
+    for gathering in [IceGathering::Full, IceGathering::Trickle] {
+        let config = WebRtcConfig { gathering, ..Default::default() };
+        let mut offerer = WebSocketSignaling::connect(&url).await?;
+        let mut answerer = WebSocketSignaling::connect(&url).await?;
+        let (result, echoed) = tokio::try_join!(
+            webrtc_remote_benchmark(&config, &mut offerer, 10, 64),
+            webrtc_echo_peer(&config, &mut answerer),
+        )?;
+        assert_eq!(result.total_bytes, 10 * 64 * 2, "{} gathering", gathering.name());
+        assert_eq!(echoed, 10);
+    }
+    server.close();
+
+    let config = WebRtcConfig::default();
+
+    // Two in-memory pipes stand in for pasting between terminals
+    let (offer_out, answer_in) = tokio::io::duplex(64 * 1024);
+    let (answer_out, offer_in) = tokio::io::duplex(64 * 1024);
+    let mut offerer = LineSignaling::new(BufReader::new(offer_in), offer_out);
+    let mut answerer = LineSignaling::new(BufReader::new(answer_in), answer_out);
+    let (result, echoed) = tokio::try_join!(
+        webrtc_remote_benchmark(&config, &mut offerer, 10, 64),
+        webrtc_echo_peer(&config, &mut answerer),
+    )?;
+    assert_eq!(result.delivery.map(|d| d.delivered), Some(10));
+    assert_eq!(echoed, 10);
+
+    Ok(())
+}
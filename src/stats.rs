@@ -0,0 +1,407 @@
+use serde::{Deserialize, Serialize};
+use std::fmt;
+use std::net::SocketAddr;
+use std::time::Duration;
+
+/// Per-message timings recorded during a run.
+#[derive(Clone, Debug, Default)]
+pub struct LatencyHistogram {
+    samples: Vec<Duration>,
+}
+
+impl LatencyHistogram {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn with_capacity(capacity: usize) -> Self {
+        Self { samples: Vec::with_capacity(capacity) }
+    }
+
+    pub fn record(&mut self, sample: Duration) {
+        self.samples.push(sample);
+    }
+
+    pub fn len(&self) -> usize {
+        self.samples.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.samples.is_empty()
+    }
+
+    /// Adds every sample from `other`, e.g. to summarise several concurrent flows together.
+    pub fn merge(&mut self, other: &LatencyHistogram) {
+        self.samples.extend_from_slice(&other.samples);
+    }
+
+    /// Summarise the recorded samples. All fields are zero if nothing was recorded.
+    pub fn stats(&self) -> LatencyStats {
+        if self.samples.is_empty() {
+            return LatencyStats::default();
+        }
+
+        let mut sorted = self.samples.clone();
+        sorted.sort_unstable();
+
+        let total: Duration = sorted.iter().sum();
+        // Mean absolute difference between consecutive samples, in arrival order
+        let jitter = if self.samples.len() > 1 {
+            let diffs: Duration = self
+                .samples
+                .windows(2)
+                .map(|w| w[1].abs_diff(w[0]))
+                .sum();
+            mean(diffs, self.samples.len() - 1)
+        } else {
+            Duration::ZERO
+        };
+
+        LatencyStats {
+            min: sorted[0],
+            p50: percentile(&sorted, 50.0),
+            p90: percentile(&sorted, 90.0),
+            p99: percentile(&sorted, 99.0),
+            p999: percentile(&sorted, 99.9),
+            max: sorted[sorted.len() - 1],
+            mean: mean(total, sorted.len()),
+            jitter,
+        }
+    }
+}
+
+// Nearest-rank percentile of an already sorted, non-empty slice
+fn percentile(sorted: &[Duration], p: f64) -> Duration {
+    let rank = (p * sorted.len() as f64 / 100.0).ceil() as usize;
+    sorted[rank.clamp(1, sorted.len()) - 1]
+}
+
+/// `total` shared evenly between `count` items, which must be non-zero. Unlike
+/// dividing a `Duration` directly, any count fits.
+pub(crate) fn mean(total: Duration, count: usize) -> Duration {
+    Duration::from_nanos((total.as_nanos() / count as u128) as u64)
+}
+
+/// Summary of a latency distribution. Serialized as milliseconds.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct LatencyStats {
+    #[serde(with = "duration_ms")]
+    pub min: Duration,
+    #[serde(with = "duration_ms")]
+    pub p50: Duration,
+    #[serde(with = "duration_ms")]
+    pub p90: Duration,
+    #[serde(with = "duration_ms")]
+    pub p99: Duration,
+    #[serde(with = "duration_ms")]
+    pub p999: Duration,
+    #[serde(with = "duration_ms")]
+    pub max: Duration,
+    #[serde(with = "duration_ms")]
+    pub mean: Duration,
+    #[serde(with = "duration_ms")]
+    pub jitter: Duration,
+}
+
+impl fmt::Display for LatencyStats {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let ms = |d: Duration| d.as_nanos() as f64 / 1_000_000.0;
+        write!(
+            f,
+            "min {:.3} / p50 {:.3} / p90 {:.3} / p99 {:.3} / p99.9 {:.3} / max {:.3} ms, jitter {:.3} ms",
+            ms(self.min), ms(self.p50), ms(self.p90), ms(self.p99), ms(self.p999), ms(self.max), ms(self.jitter)
+        )
+    }
+}
+
+/// Outcome of a single transport benchmark run.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct BenchResult {
+    /// Wall-clock time of the measured loop.
+    #[serde(with = "duration_ms")]
+    pub elapsed: Duration,
+    /// Bytes moved in both directions.
+    pub total_bytes: usize,
+    /// Per-message latency distribution.
+    pub latency: LatencyStats,
+    /// Where the server end was bound, so runs on port 0 can report the port chosen.
+    #[serde(default)]
+    pub server_addr: Option<SocketAddr>,
+    /// Messages sent and delivered, for transports that can drop messages.
+    #[serde(default)]
+    pub delivery: Option<Delivery>,
+    /// Time spent connecting, kept apart from the steady-state numbers above.
+    /// `None` when the run reused an existing connection.
+    #[serde(default)]
+    pub setup: Option<SetupTimings>,
+    /// How evenly concurrent flows shared the link, for runs with more than one.
+    #[serde(default)]
+    pub fairness: Option<Fairness>,
+    /// Where proving and verifying spent their time, for proof-only runs.
+    #[serde(default)]
+    pub phases: Option<ProofPhases>,
+}
+
+impl BenchResult {
+    pub fn new(elapsed: Duration, total_bytes: usize, histogram: &LatencyHistogram) -> Self {
+        Self {
+            elapsed,
+            total_bytes,
+            latency: histogram.stats(),
+            server_addr: None,
+            delivery: None,
+            setup: None,
+            fairness: None,
+            phases: None,
+        }
+    }
+
+    pub fn with_server_addr(mut self, addr: SocketAddr) -> Self {
+        self.server_addr = Some(addr);
+        self
+    }
+
+    pub fn with_delivery(mut self, sent: u64, delivered: u64) -> Self {
+        self.delivery = Some(Delivery { sent, delivered });
+        self
+    }
+
+    pub fn with_setup(mut self, setup: SetupTimings) -> Self {
+        self.setup = Some(setup);
+        self
+    }
+
+    pub fn with_fairness(mut self, fairness: Fairness) -> Self {
+        self.fairness = Some(fairness);
+        self
+    }
+
+    pub fn with_phases(mut self, phases: ProofPhases) -> Self {
+        self.phases = Some(phases);
+        self
+    }
+
+    /// Throughput in MB/s over the whole run.
+    pub fn throughput_mbps(&self) -> f64 {
+        self.total_bytes as f64 / self.elapsed.as_secs_f64() / 1_000_000.0
+    }
+}
+
+/// How many of the messages sent in a run made it to the far end.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Delivery {
+    pub sent: u64,
+    pub delivered: u64,
+}
+
+impl Delivery {
+    /// Fraction of sent messages that were delivered, 1.0 if nothing was sent.
+    pub fn ratio(&self) -> f64 {
+        if self.sent == 0 {
+            1.0
+        } else {
+            self.delivered as f64 / self.sent as f64
+        }
+    }
+}
+
+/// Spread of throughput across flows that ran at the same time.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Serialize, Deserialize)]
+pub struct Fairness {
+    pub flows: usize,
+    pub min_mbps: f64,
+    pub max_mbps: f64,
+    /// Jain's fairness index: 1.0 when every flow got the same throughput,
+    /// down to 1/flows when one flow got all of it.
+    pub jain_index: f64,
+}
+
+impl Fairness {
+    /// Summarises the throughput each flow achieved, in MB/s.
+    pub fn new(throughputs_mbps: &[f64]) -> Self {
+        let sum: f64 = throughputs_mbps.iter().sum();
+        let sum_of_squares: f64 = throughputs_mbps.iter().map(|x| x * x).sum();
+        let jain_index = if sum_of_squares > 0.0 {
+            sum * sum / (throughputs_mbps.len() as f64 * sum_of_squares)
+        } else {
+            1.0
+        };
+        Self {
+            flows: throughputs_mbps.len(),
+            min_mbps: throughputs_mbps.iter().copied().reduce(f64::min).unwrap_or(0.0),
+            max_mbps: throughputs_mbps.iter().copied().reduce(f64::max).unwrap_or(0.0),
+            jain_index,
+        }
+    }
+}
+
+impl fmt::Display for Fairness {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{} flows, min {:.2} / max {:.2} MB/s, Jain index {:.3}",
+            self.flows, self.min_mbps, self.max_mbps, self.jain_index
+        )
+    }
+}
+
+/// Time spent in each stage of proving and verifying a payload. `setup` is paid
+/// once; the other stages are means per proof.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ProofPhases {
+    /// Parameter or circuit generation.
+    #[serde(with = "duration_ms")]
+    pub setup: Duration,
+    /// Computing every wire value from the payload.
+    #[serde(with = "duration_ms")]
+    pub witness: Duration,
+    #[serde(with = "duration_ms")]
+    pub prove: Duration,
+    /// Writing the proof out as bytes.
+    #[serde(with = "duration_ms")]
+    pub serialize: Duration,
+    /// Reading the proof back and checking it.
+    #[serde(with = "duration_ms")]
+    pub verify: Duration,
+    /// Serialized proof size in bytes.
+    pub proof_size: usize,
+}
+
+impl fmt::Display for ProofPhases {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let ms = |d: Duration| d.as_nanos() as f64 / 1_000_000.0;
+        write!(
+            f,
+            "setup {:.3} / witness {:.3} / prove {:.3} / serialize {:.3} / verify {:.3} ms, proof {} bytes",
+            ms(self.setup), ms(self.witness), ms(self.prove), ms(self.serialize), ms(self.verify), self.proof_size
+        )
+    }
+}
+
+/// Time spent establishing a connection, split into the phases a transport goes
+/// through, in order.
+#[derive(Clone, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct SetupTimings {
+    pub phases: Vec<SetupPhase>,
+    /// From starting to connect until the first message arrived, when measured.
+    #[serde(default, with = "duration_ms::option")]
+    pub time_to_first_byte: Option<Duration>,
+}
+
+/// A single connection setup step, e.g. `tcp_connect` or `dtls`.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct SetupPhase {
+    pub name: String,
+    #[serde(with = "duration_ms")]
+    pub elapsed: Duration,
+}
+
+impl SetupTimings {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn push(&mut self, name: &str, elapsed: Duration) {
+        self.phases.push(SetupPhase { name: name.to_owned(), elapsed });
+    }
+
+    /// Sum of all phases.
+    pub fn total(&self) -> Duration {
+        self.phases.iter().map(|phase| phase.elapsed).sum()
+    }
+}
+
+impl fmt::Display for SetupTimings {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let ms = |d: Duration| d.as_nanos() as f64 / 1_000_000.0;
+        for (i, phase) in self.phases.iter().enumerate() {
+            if i > 0 {
+                write!(f, " / ")?;
+            }
+            write!(f, "{} {:.3}", phase.name, ms(phase.elapsed))?;
+        }
+        write!(f, " ms (total {:.3} ms", ms(self.total()))?;
+        if let Some(ttfb) = self.time_to_first_byte {
+            write!(f, ", first byte {:.3} ms", ms(ttfb))?;
+        }
+        write!(f, ")")
+    }
+}
+
+/// Length of the sequence number that starts every message in benchmarks whose
+/// transport can lose, duplicate or reorder messages.
+pub(crate) const SEQ_LEN: usize = 8;
+
+/// Reads the sequence number a benchmark message starts with.
+pub(crate) fn read_seq(msg: &[u8]) -> Option<u64> {
+    Some(u64::from_be_bytes(msg.get(..SEQ_LEN)?.try_into().ok()?))
+}
+
+/// (De)serializes a `Duration` as fractional milliseconds, which is easier to
+/// read and diff than serde's default `{ secs, nanos }` form.
+pub mod duration_ms {
+    use serde::{Deserialize, Deserializer, Serializer};
+    use std::time::Duration;
+
+    pub fn serialize<S: Serializer>(duration: &Duration, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_f64(duration.as_secs_f64() * 1_000.0)
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Duration, D::Error> {
+        let ms = f64::deserialize(deserializer)?;
+        Duration::try_from_secs_f64(ms / 1_000.0).map_err(serde::de::Error::custom)
+    }
+
+    /// The same for an `Option<Duration>`, with `None` as null.
+    pub mod option {
+        use serde::{Deserialize, Deserializer, Serializer};
+        use std::time::Duration;
+
+        pub fn serialize<S: Serializer>(duration: &Option<Duration>, serializer: S) -> Result<S::Ok, S::Error> {
+            match duration {
+                Some(duration) => super::serialize(duration, serializer),
+                None => serializer.serialize_none(),
+            }
+        }
+
+        pub fn deserialize<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Option<Duration>, D::Error> {
+            match Option::<f64>::deserialize(deserializer)? {
+                Some(ms) => Duration::try_from_secs_f64(ms / 1_000.0).map(Some).map_err(serde::de::Error::custom),
+                None => Ok(None),
+            }
+        }
+    }
+}
+
+#[test]
+fn test_latency_stats() {
+    let mut histogram = LatencyHistogram::new();
+    for ms in (1..=1000).rev() {
+        histogram.record(Duration::from_millis(ms));
+    }
+
+    let stats = histogram.stats();
+    assert_eq!(stats.min, Duration::from_millis(1));
+    assert_eq!(stats.p50, Duration::from_millis(500));
+    assert_eq!(stats.p90, Duration::from_millis(900));
+    assert_eq!(stats.p99, Duration::from_millis(990));
+    assert_eq!(stats.p999, Duration::from_millis(999));
+    assert_eq!(stats.max, Duration::from_millis(1000));
+    assert_eq!(stats.jitter, Duration::from_millis(1));
+    assert_eq!(LatencyHistogram::new().stats(), LatencyStats::default());
+
+    // Counts beyond u32::MAX aren't truncated
+    let count = u32::MAX as usize + 2;
+    assert_eq!(mean(Duration::from_nanos(2 * count as u64), count), Duration::from_nanos(2));
+}
+
+#[test]
+fn test_fairness() {
+    let even = Fairness::new(&[2.0, 2.0, 2.0, 2.0]);
+    assert_eq!(even.flows, 4);
+    assert_eq!(even.jain_index, 1.0);
+    let starved = Fairness::new(&[8.0, 0.0, 0.0, 0.0]);
+    assert_eq!(starved.jain_index, 0.25);
+    assert_eq!((starved.min_mbps, starved.max_mbps), (0.0, 8.0));
+    assert_eq!(Fairness::new(&[]).min_mbps, 0.0);
+}
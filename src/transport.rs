@@ -0,0 +1,220 @@
+use anyhow::{Context, Result};
+use async_trait::async_trait;
+use std::net::{Ipv4Addr, SocketAddr};
+use std::time::Instant;
+use tokio::net::{TcpListener, TcpSocket, TcpStream};
+use tokio::sync::oneshot;
+use tokio::task::JoinHandle;
+
+use crate::reliable_udp::ReliableUdpConfig;
+use crate::stats::{BenchResult, LatencyHistogram, SetupTimings};
+use crate::udp_ping_pong::UdpConfig;
+use crate::webrtc_benchmark::WebRtcConfig;
+
+/// Addresses the two ends of a loopback connection bind to.
+///
+/// Port 0 lets the OS pick a free port. WebRTC negotiates its own candidates
+/// and ignores the addresses, taking its settings from `webrtc` instead.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct TransportConfig {
+    /// Address the server (echoing) end listens on.
+    pub server_addr: SocketAddr,
+    /// Address the client end binds to before connecting.
+    pub client_addr: SocketAddr,
+    /// Messages `udp_ping_pong` and `websocket_benchmark` keep in flight at once.
+    /// 1 waits for every echo before sending the next message.
+    pub window: usize,
+    pub udp: UdpConfig,
+    pub reliable_udp: ReliableUdpConfig,
+    pub webrtc: WebRtcConfig,
+}
+
+impl Default for TransportConfig {
+    fn default() -> Self {
+        let localhost = SocketAddr::from((Ipv4Addr::LOCALHOST, 0));
+        Self {
+            server_addr: localhost,
+            client_addr: localhost,
+            window: 1,
+            udp: UdpConfig::default(),
+            reliable_udp: ReliableUdpConfig::default(),
+            webrtc: WebRtcConfig::default(),
+        }
+    }
+}
+
+/// A message-oriented, bidirectional connection used by the benchmarks.
+///
+/// Every implementation hands back both ends of a loopback connection from
+/// [`Transport::connect`], so a single driver can run the same workload over
+/// UDP, WebSocket, WebRTC, ... and the numbers stay comparable.
+#[async_trait]
+pub trait Transport: Send + Sized + 'static {
+    /// Short name used when printing results.
+    const NAME: &'static str;
+
+    /// Establish a loopback connection and return `(client, server)`.
+    async fn connect(config: &TransportConfig) -> Result<(Self, Self)>;
+
+    /// Send a single message.
+    async fn send(&mut self, msg: &[u8]) -> Result<()>;
+
+    /// Receive a single message.
+    async fn recv(&mut self) -> Result<Vec<u8>>;
+
+    /// Tear the connection down.
+    async fn close(self) -> Result<()>;
+
+    /// The address this end is bound to, if the transport has a single socket.
+    fn local_addr(&self) -> Option<SocketAddr> {
+        None
+    }
+
+    /// How long each phase of [`Transport::connect`] took, if the transport
+    /// breaks it down. Only the client end reports this.
+    fn setup_timings(&self) -> Option<SetupTimings> {
+        None
+    }
+}
+
+/// Runs `iterations` round trips of `msg_size` bytes over `T`.
+///
+/// The server end echoes every message back to the client, and every round trip
+/// is recorded individually. Connecting is timed separately and reported in
+/// [`BenchResult::setup`].
+pub async fn ping_pong<T: Transport>(config: &TransportConfig, iterations: u64, msg_size: usize) -> Result<BenchResult> {
+    let mut session = EchoSession::<T>::connect(config).await?;
+    let result = session.ping_pong(iterations, msg_size).await?;
+    let setup = session.setup().clone();
+    session.close().await?;
+    Ok(result.with_setup(setup))
+}
+
+/// A connected client whose server end echoes in the background, so several
+/// runs can share one connection instead of paying for setup every time.
+pub struct EchoSession<T: Transport> {
+    client: T,
+    echo: JoinHandle<Result<T>>,
+    stop: oneshot::Sender<()>,
+    server_addr: Option<SocketAddr>,
+    setup: SetupTimings,
+}
+
+impl<T: Transport> EchoSession<T> {
+    pub async fn connect(config: &TransportConfig) -> Result<Self> {
+        let (client, mut server, setup) = connect_timed::<T>(config).await?;
+        let server_addr = server.local_addr();
+
+        let (stop, mut stopped) = oneshot::channel();
+        let echo = tokio::spawn(async move {
+            loop {
+                tokio::select! {
+                    _ = &mut stopped => break,
+                    msg = server.recv() => server.send(&msg?).await?,
+                }
+            }
+            Result::<T>::Ok(server)
+        });
+
+        Ok(Self { client, echo, stop, server_addr, setup })
+    }
+
+    /// Phases it took to establish this session.
+    pub fn setup(&self) -> &SetupTimings {
+        &self.setup
+    }
+
+    /// Runs `iterations` round trips over the existing connection. The result
+    /// carries no setup timings.
+    pub async fn ping_pong(&mut self, iterations: u64, msg_size: usize) -> Result<BenchResult> {
+        let msg = vec![0; msg_size];
+        let mut total_bytes = 0;
+        let mut histogram = LatencyHistogram::with_capacity(iterations as usize);
+
+        let start = Instant::now();
+        for _ in 0..iterations {
+            let sent = Instant::now();
+            self.client.send(&msg).await?;
+            let reply = self.client.recv().await?;
+            histogram.record(sent.elapsed());
+            total_bytes += msg.len() + reply.len();
+        }
+        let elapsed = start.elapsed();
+
+        let result = BenchResult::new(elapsed, total_bytes, &histogram);
+        Ok(match self.server_addr {
+            Some(addr) => result.with_server_addr(addr),
+            None => result,
+        })
+    }
+
+    pub async fn close(self) -> Result<()> {
+        let _ = self.stop.send(());
+        let server = self.echo.await??;
+        self.client.close().await?;
+        server.close().await?;
+        Ok(())
+    }
+}
+
+/// Like [`Transport::connect`], also returning how long setup took. Transports
+/// that don't break setup down report a single `connect` phase.
+pub async fn connect_timed<T: Transport>(config: &TransportConfig) -> Result<(T, T, SetupTimings)> {
+    let start = Instant::now();
+    let (client, server) = T::connect(config).await?;
+    let setup = client.setup_timings().unwrap_or_else(|| {
+        let mut setup = SetupTimings::new();
+        setup.push("connect", start.elapsed());
+        setup
+    });
+    Ok((client, server, setup))
+}
+
+/// Connects to `server` from a socket bound to `local`.
+pub async fn connect_tcp(local: SocketAddr, server: SocketAddr) -> Result<TcpStream> {
+    let socket = if local.is_ipv4() { TcpSocket::new_v4()? } else { TcpSocket::new_v6()? };
+    socket
+        .bind(local)
+        .with_context(|| format!("Failed to bind TCP socket to {}", local))?;
+    Ok(socket.connect(server).await?)
+}
+
+/// Binds a listener to `addr`.
+pub async fn bind_tcp(addr: SocketAddr) -> Result<TcpListener> {
+    TcpListener::bind(addr)
+        .await
+        .with_context(|| format!("Failed to bind TCP listener to {}", addr))
+}
+
+#[tokio::test]
+async fn test_ping_pong_transports() -> Result<()> {
+    use crate::{Http2Transport, UdpTransport, WebRtcTransport, WebSocketTransport};
+
+    let config = TransportConfig::default();
+
+    let result = ping_pong::<UdpTransport>(&config, 10, 64).await?;
+    assert_eq!(result.total_bytes, 10 * 64 * 2);
+    assert!(result.latency.min <= result.latency.p50 && result.latency.p50 <= result.latency.max);
+
+    let result = ping_pong::<WebSocketTransport>(&config, 10, 64).await?;
+    assert_eq!(result.total_bytes, 10 * 64 * 2);
+
+    let result = ping_pong::<Http2Transport>(&config, 10, 64).await?;
+    assert_eq!(result.total_bytes, 10 * 64 * 2);
+
+    let result = ping_pong::<WebRtcTransport>(&config, 10, 64).await?;
+    assert_eq!(result.total_bytes, 10 * 64 * 2);
+    assert!(result.setup.is_some_and(|setup| setup.phases.len() == 4));
+
+    // A reused session keeps echoing across runs
+    let mut session = EchoSession::<UdpTransport>::connect(&config).await?;
+    for _ in 0..2 {
+        let result = session.ping_pong(10, 64).await?;
+        assert_eq!(result.total_bytes, 10 * 64 * 2);
+        assert!(result.setup.is_none());
+    }
+    assert_eq!(session.setup().phases[0].name, "connect");
+    session.close().await?;
+
+    Ok(())
+}
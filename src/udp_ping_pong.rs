@@ -1,36 +1,219 @@
-use std::time::Instant;
+use std::collections::BTreeMap;
+use std::net::SocketAddr;
+use std::time::{Duration, Instant};
 use tokio::net::UdpSocket;
-use anyhow::Result;
+use tokio::sync::oneshot;
+use anyhow::{anyhow, Context, Result};
+use async_trait::async_trait;
 
-pub async fn udp_ping_pong(iterations: u64, msg_size: usize) -> Result<(std::time::Duration, usize)> {
-    let addr1 = "127.0.0.1:8888";
-    let addr2 = "127.0.0.1:9999";
+use crate::impairment::{ImpairedUdpSocket, NetworkImpairment};
+use crate::stats::{read_seq, BenchResult, LatencyHistogram, SEQ_LEN};
+use crate::transport::{Transport, TransportConfig};
 
-    let socket1 = UdpSocket::bind(addr1).await.unwrap();
-    let socket2 = UdpSocket::bind(addr2).await.unwrap();
+/// UDP-specific settings.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct UdpConfig {
+    /// Applied to datagrams sent from either end.
+    pub impairment: NetworkImpairment,
+    /// How long the client waits for a reply before counting the round trip as lost.
+    pub recv_timeout: Duration,
+}
 
-    let msg = vec![0; msg_size];
+impl Default for UdpConfig {
+    fn default() -> Self {
+        Self { impairment: NetworkImpairment::default(), recv_timeout: Duration::from_secs(1) }
+    }
+}
+
+/// Raw UDP ping-pong between a client socket bound to `config.client_addr` and an
+/// echo socket bound to `config.server_addr`. The address the echo socket ended
+/// up on is reported in the result.
+///
+/// Every datagram starts with its sequence number, so `msg_size` must be at
+/// least 8. Up to `config.window` datagrams are in flight at once, each timed
+/// from its own send. A round trip whose echo doesn't arrive within
+/// `config.udp.recv_timeout` is counted as lost, and the result reports how many
+/// were delivered.
+pub async fn udp_ping_pong(config: &TransportConfig, iterations: u64, msg_size: usize) -> Result<BenchResult> {
+    if msg_size < SEQ_LEN {
+        return Err(anyhow!("UDP messages need at least {} bytes, got {}", SEQ_LEN, msg_size));
+    }
+    if config.window == 0 {
+        return Err(anyhow!("window must be at least 1"));
+    }
+
+    let impairment = config.udp.impairment;
+    let socket1 = ImpairedUdpSocket::new(bind_udp(config.client_addr).await?, impairment)?;
+    let socket2 = ImpairedUdpSocket::new(bind_udp(config.server_addr).await?, impairment.reversed())?;
+    let addr1 = socket1.local_addr()?;
+    let addr2 = socket2.local_addr()?;
 
+    // Echo until told to stop, since the client can't say how many datagrams got through
+    let (stop_tx, mut stop_rx) = oneshot::channel::<()>();
     let handle = tokio::spawn(async move {
         let mut buf = vec![0; msg_size];
-        for _ in 0..iterations {
-            let (len, _) = socket2.recv_from(&mut buf).await.unwrap();
-            socket2.send_to(&buf[..len], addr1).await.unwrap();
+        loop {
+            tokio::select! {
+                _ = &mut stop_rx => break,
+                received = socket2.recv_from(&mut buf) => {
+                    let (len, _) = received?;
+                    socket2.send_to(&buf[..len], addr1).await?;
+                }
+            }
         }
+        Result::<()>::Ok(())
     });
 
+    let mut msg = vec![0; msg_size];
+    let mut buf = vec![0; msg_size];
+    let mut total_bytes = 0;
+    let mut delivered = 0;
+    let mut histogram = LatencyHistogram::with_capacity(iterations as usize);
     let start = Instant::now();
 
-    for _ in 0..iterations {
-        socket1.send_to(&msg, addr2).await.unwrap();
-        let mut buf = vec![0; msg_size];
-        socket1.recv_from(&mut buf).await.unwrap();
-    }
+    // Send times of the datagrams still waiting for an echo, by sequence number
+    let mut in_flight = BTreeMap::new();
+    let mut next_seq = 0;
+    while next_seq < iterations || !in_flight.is_empty() {
+        while next_seq < iterations && in_flight.len() < config.window {
+            msg[..SEQ_LEN].copy_from_slice(&next_seq.to_be_bytes());
+            in_flight.insert(next_seq, Instant::now());
+            socket1.send_to(&msg, addr2).await?;
+            next_seq += 1;
+        }
 
-    handle.await.unwrap();
+        // Take the next echo, dropping duplicates and late echoes, until the oldest
+        // datagram in flight runs out of time
+        let (&oldest, &sent) = in_flight.iter().next().unwrap();
+        let deadline = tokio::time::Instant::from_std(sent + config.udp.recv_timeout);
+        match tokio::time::timeout_at(deadline, socket1.recv_from(&mut buf)).await {
+            Ok(received) => {
+                let (len, _) = received?;
+                if let Some(sent) = read_seq(&buf[..len]).and_then(|seq| in_flight.remove(&seq)) {
+                    histogram.record(sent.elapsed());
+                    total_bytes += msg_size + len;
+                    delivered += 1;
+                }
+            }
+            Err(_) => {
+                in_flight.remove(&oldest);
+            }
+        }
+    }
 
     let elapsed = start.elapsed();
-    let total_bytes = iterations * msg_size as u64 * 2;
+    let _ = stop_tx.send(());
+    handle.await??;
+
+    Ok(BenchResult::new(elapsed, total_bytes, &histogram)
+        .with_server_addr(addr2)
+        .with_delivery(iterations, delivered))
+}
+
+pub(crate) async fn bind_udp(addr: SocketAddr) -> Result<UdpSocket> {
+    UdpSocket::bind(addr)
+        .await
+        .with_context(|| format!("Failed to bind UDP socket to {}", addr))
+}
+
+/// Largest payload a single UDP datagram can carry over IPv4.
+pub(crate) const MAX_DATAGRAM_SIZE: usize = 65_507;
+
+/// UDP socket connected to a single peer; one datagram per message.
+///
+/// The client end gives up on a message after `config.udp.recv_timeout`, so a
+/// lost datagram fails the run instead of hanging it.
+pub struct UdpTransport {
+    socket: ImpairedUdpSocket,
+    buf: Vec<u8>,
+    /// Only set on the client end; the server waits as long as it takes.
+    recv_timeout: Option<Duration>,
+}
+
+#[async_trait]
+impl Transport for UdpTransport {
+    const NAME: &'static str = "UDP";
+
+    async fn connect(config: &TransportConfig) -> Result<(Self, Self)> {
+        let impairment = config.udp.impairment;
+        let socket1 = ImpairedUdpSocket::new(bind_udp(config.client_addr).await?, impairment)?;
+        let socket2 = ImpairedUdpSocket::new(bind_udp(config.server_addr).await?, impairment.reversed())?;
+
+        socket1.connect(socket2.local_addr()?).await?;
+        socket2.connect(socket1.local_addr()?).await?;
+
+        Ok((
+            Self { socket: socket1, buf: vec![0; MAX_DATAGRAM_SIZE], recv_timeout: Some(config.udp.recv_timeout) },
+            Self { socket: socket2, buf: vec![0; MAX_DATAGRAM_SIZE], recv_timeout: None },
+        ))
+    }
+
+    async fn send(&mut self, msg: &[u8]) -> Result<()> {
+        if msg.len() > MAX_DATAGRAM_SIZE {
+            return Err(anyhow!("message of {} bytes does not fit in a UDP datagram", msg.len()));
+        }
+        self.socket.send(msg).await?;
+        Ok(())
+    }
+
+    async fn recv(&mut self) -> Result<Vec<u8>> {
+        let len = match self.recv_timeout {
+            Some(timeout) => tokio::time::timeout(timeout, self.socket.recv(&mut self.buf))
+                .await
+                .map_err(|_| anyhow!("No UDP datagram within {:?}", timeout))??,
+            None => self.socket.recv(&mut self.buf).await?,
+        };
+        Ok(self.buf[..len].to_vec())
+    }
+
+    async fn close(self) -> Result<()> {
+        Ok(())
+    }
+
+    fn local_addr(&self) -> Option<SocketAddr> {
+        self.socket.local_addr().ok()
+    }
+}
+
+#[tokio::test]
+async fn test_udp_ping_pong_ephemeral_ports() -> Result<()> {
+    let result = udp_ping_pong(&TransportConfig::default(), 10, 64).await?;
+    assert_ne!(result.server_addr.map(|addr| addr.port()), Some(0));
+    assert!(result.server_addr.is_some());
+
+    // A port that is already taken is an error, not a panic
+    let taken = UdpSocket::bind("127.0.0.1:0").await?;
+    let config = TransportConfig { server_addr: taken.local_addr()?, ..Default::default() };
+    assert!(udp_ping_pong(&config, 10, 64).await.is_err());
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_udp_ping_pong_impaired() -> Result<()> {
+    // Half of all datagrams lost each way, so roughly a quarter of round trips complete
+    let impairment = NetworkImpairment { loss: 0.5, seed: Some(7), ..Default::default() };
+    let udp = UdpConfig { impairment, recv_timeout: Duration::from_millis(20) };
+    let config = TransportConfig { udp, ..Default::default() };
+    let result = udp_ping_pong(&config, 40, 64).await?;
+    let delivery = result.delivery.unwrap();
+    assert_eq!(delivery.sent, 40);
+    assert!(delivery.delivered > 0 && delivery.delivered < 40, "{:?}", delivery);
+    assert_eq!(result.total_bytes, delivery.delivered as usize * 64 * 2);
+
+    // Duplicates are not counted twice, and latency applies in both directions
+    let impairment = NetworkImpairment { latency: Duration::from_millis(5), duplicate: 1.0, ..Default::default() };
+    let config = TransportConfig { udp: UdpConfig { impairment, ..Default::default() }, ..Default::default() };
+    let result = udp_ping_pong(&config, 10, 64).await?;
+    assert_eq!(result.delivery.map(|d| d.delivered), Some(10));
+    assert!(result.latency.min >= Duration::from_millis(10));
+
+    // With 8 round trips in flight the run takes about an eighth as long
+    let config = TransportConfig { window: 8, ..config };
+    let result = udp_ping_pong(&config, 40, 64).await?;
+    assert_eq!(result.delivery.map(|d| d.delivered), Some(40));
+    assert!(result.latency.min >= Duration::from_millis(10));
+    assert!(result.elapsed < Duration::from_millis(200), "{:?}", result.elapsed);
 
-    Ok((elapsed, total_bytes as usize))
-} 
\ No newline at end of file
+    Ok(())
+}
@@ -1,23 +1,669 @@
-use anyhow::Result;
+use anyhow::{anyhow, Result};
+use async_trait::async_trait;
 use bytes::Bytes;
+use futures_util::future::{join_all, try_join_all};
+use futures_util::FutureExt;
+use std::net::{Ipv4Addr, SocketAddr};
+use std::str::FromStr;
 use std::sync::Arc;
-use std::time::Instant;
-use tokio::sync::mpsc;
+use std::time::{Duration, Instant};
+use tokio::sync::{mpsc, oneshot, Mutex};
 use webrtc::api::interceptor_registry::register_default_interceptors;
 use webrtc::api::media_engine::MediaEngine;
+use webrtc::api::setting_engine::SettingEngine;
 use webrtc::api::APIBuilder;
+use webrtc::data_channel::data_channel_init::RTCDataChannelInit;
 use webrtc::data_channel::data_channel_message::DataChannelMessage;
-use webrtc::ice_transport::ice_candidate::RTCIceCandidateInit;
-use webrtc::ice_transport::ice_server::RTCIceServer;
+use webrtc::data_channel::RTCDataChannel;
+use webrtc::ice::network_type::NetworkType;
+use webrtc::ice_transport::ice_candidate::RTCIceCandidate;
+use webrtc::ice_transport::ice_connection_state::RTCIceConnectionState;
 use webrtc::interceptor::registry::Registry;
 use webrtc::peer_connection::configuration::RTCConfiguration;
 use webrtc::peer_connection::peer_connection_state::RTCPeerConnectionState;
+use webrtc::peer_connection::policy::ice_transport_policy::RTCIceTransportPolicy;
 use webrtc::peer_connection::sdp::session_description::RTCSessionDescription;
+use webrtc::peer_connection::RTCPeerConnection;
+use webrtc_util::vnet::net::{Net, NetConfig};
+use webrtc_util::vnet::router::{Router, RouterConfig};
 
+use crate::ice_server::LocalIceServer;
+use crate::signaling::{InProcessSignaling, SignalMessage, Signaling};
+use crate::stats::{read_seq, BenchResult, Fairness, LatencyHistogram, SetupTimings, SEQ_LEN};
+use crate::transport::{Transport, TransportConfig};
+
+/// What [`webrtc_benchmark`] measures.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum WebRtcBenchMode {
+    /// `dc2` echoes every message and the sender waits for it before sending the
+    /// next, recording the round trip, like the UDP and WebSocket benchmarks.
+    #[default]
+    RoundTrip,
+    /// Every message is sent back to back without waiting. Latency is measured
+    /// from the moment a message is handed to `dc` until it arrives at `dc2`, so
+    /// it includes time spent queued behind earlier messages.
+    OneWay,
+}
+
+/// Sends `iterations` messages of `msg_size` bytes from `dc` to `dc2`, measured
+/// as described by `mode`, over a data channel set up from `config`.
+///
+/// Every message starts with its sequence number, so `msg_size` must be at
+/// least 8. On unreliable channels, messages that don't arrive within
+/// `config.recv_timeout` are counted as lost, and the result reports how many
+/// were delivered.
 pub async fn webrtc_benchmark(
+    config: &WebRtcConfig,
+    mode: WebRtcBenchMode,
+    iterations: u64,
+    msg_size: usize,
+) -> Result<BenchResult> {
+    check_msg_size(msg_size)?;
+
+    let (mut offerer, mut answerer) = connect_peers(config).await?;
+
+    let run = match mode {
+        WebRtcBenchMode::RoundTrip => {
+            let mut echoed = 0;
+            tokio::select! {
+                run = round_trip(config, offerer.first_channel(), iterations, msg_size) => run,
+                result = echo(answerer.first_channel(), &mut echoed) => {
+                    result.and_then(|_| Err(anyhow!("Echo peer stopped after {} messages", echoed)))
+                }
+            }
+        }
+        WebRtcBenchMode::OneWay => {
+            let receiver = &mut answerer.first_channel().rx;
+            one_way(config, &offerer.channels[0].dc, receiver, iterations, msg_size).await
+        }
+    };
+    let run = run.map(|run| {
+        let setup = run.setup_with_first_byte(&offerer);
+        (run, setup)
+    });
+
+    // Close peer connections, whether or not the run succeeded
+    let offerer_closed = offerer.close().await;
+    let answerer_closed = answerer.close().await;
+    let (run, setup) = run?;
+    offerer_closed?;
+    answerer_closed?;
+
+    Ok(BenchResult::new(run.elapsed, run.total_bytes, &run.histogram)
+        .with_delivery(iterations, run.delivered)
+        .with_setup(setup))
+}
+
+/// Offers a connection through `signaling` and measures round trips against a
+/// [`webrtc_echo_peer`] on the other end, which may be in another process.
+pub async fn webrtc_remote_benchmark(
+    config: &WebRtcConfig,
+    signaling: &mut dyn Signaling,
+    iterations: u64,
+    msg_size: usize,
+) -> Result<BenchResult> {
+    check_msg_size(msg_size)?;
+
+    let mut peer = connect_peer(PeerRole::Offerer, config, signaling).await?;
+    let run = round_trip(config, peer.first_channel(), iterations, msg_size).await;
+    let run = run.map(|run| {
+        let setup = run.setup_with_first_byte(&peer);
+        (run, setup)
+    });
+    let closed = peer.close().await;
+    let (run, setup) = run?;
+    closed?;
+
+    Ok(BenchResult::new(run.elapsed, run.total_bytes, &run.histogram)
+        .with_delivery(iterations, run.delivered)
+        .with_setup(setup))
+}
+
+/// Answers an offer from [`webrtc_remote_benchmark`] through `signaling` and
+/// echoes every message until the offerer hangs up. Returns the number of
+/// messages echoed.
+pub async fn webrtc_echo_peer(config: &WebRtcConfig, signaling: &mut dyn Signaling) -> Result<u64> {
+    let mut peer = connect_peer(PeerRole::Answerer, config, signaling).await?;
+
+    // The data channel closes as soon as the offerer shuts down cleanly; the
+    // connection state covers offerers that vanish without saying goodbye
+    let (closed_tx, mut closed_rx) = mpsc::channel(1);
+    let dc_closed_tx = closed_tx.clone();
+    peer.channels[0].dc.on_close(Box::new(move || {
+        let _ = dc_closed_tx.try_send(());
+        Box::pin(async {})
+    }));
+    peer.pc.on_peer_connection_state_change(Box::new(move |state| {
+        let closed_tx = closed_tx.clone();
+        Box::pin(async move {
+            if matches!(
+                state,
+                RTCPeerConnectionState::Disconnected | RTCPeerConnectionState::Failed | RTCPeerConnectionState::Closed
+            ) {
+                let _ = closed_tx.send(()).await;
+            }
+        })
+    }));
+
+    let mut echoed = 0;
+    tokio::select! {
+        result = echo(peer.first_channel(), &mut echoed) => result?,
+        _ = closed_rx.recv() => {}
+    }
+
+    peer.close().await?;
+    Ok(echoed)
+}
+
+/// Which peers connect to each other in [`webrtc_fanout_benchmark`].
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum WebRtcTopology {
+    /// The first peer connects to every other peer and sends to all of them,
+    /// like one prover fanning proof updates out to its subscribers.
+    #[default]
+    Star,
+    /// Every peer connects to and sends to every other peer.
+    Mesh,
+}
+
+impl WebRtcTopology {
+    pub fn name(&self) -> &'static str {
+        match self {
+            WebRtcTopology::Star => "star",
+            WebRtcTopology::Mesh => "mesh",
+        }
+    }
+}
+
+impl FromStr for WebRtcTopology {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s.to_ascii_lowercase().as_str() {
+            "star" => Ok(WebRtcTopology::Star),
+            "mesh" => Ok(WebRtcTopology::Mesh),
+            other => Err(anyhow!("Unknown topology: {}", other)),
+        }
+    }
+}
+
+/// Connects `peers` peers in `topology` within this process, each connection
+/// carrying `config.channels` data channels, then sends `iterations` messages
+/// of `msg_size` bytes one way over every channel in every sending direction
+/// at once.
+///
+/// Every channel and direction is a separate flow. The result covers all flows
+/// together: throughput is the bytes delivered over the time until the slowest
+/// flow finished, and latency is measured as in [`WebRtcBenchMode::OneWay`].
+/// Its fairness shows how evenly the flows shared the link.
+pub async fn webrtc_fanout_benchmark(
+    config: &WebRtcConfig,
+    topology: WebRtcTopology,
+    peers: usize,
     iterations: u64,
     msg_size: usize,
-) -> Result<(std::time::Duration, usize)> {
+) -> Result<BenchResult> {
+    check_msg_size(msg_size)?;
+    if peers < 2 {
+        return Err(anyhow!("A {} needs at least 2 peers, got {}", topology.name(), peers));
+    }
+
+    // Peer 0 is the hub of a star; a mesh links every pair
+    let links = match topology {
+        WebRtcTopology::Star => peers - 1,
+        WebRtcTopology::Mesh => peers * (peers - 1) / 2,
+    };
+    let mut connections = Vec::with_capacity(links);
+    let mut error = None;
+    for connection in join_all((0..links).map(|_| connect_peers(config))).await {
+        match connection {
+            Ok(connection) => connections.push(connection),
+            Err(e) => {
+                error.get_or_insert(e);
+            }
+        }
+    }
+    if let Some(e) = error {
+        let _ = close_connections(connections).await;
+        return Err(e);
+    }
+
+    // Each flow is one channel in one direction: hub to leaf in a star, both ways in a mesh
+    let mut flows = Vec::new();
+    for (offerer, answerer) in connections.iter_mut() {
+        for (sender, receiver) in offerer.channels.iter_mut().zip(answerer.channels.iter_mut()) {
+            flows.push((sender.dc.clone(), &mut receiver.rx));
+            if topology == WebRtcTopology::Mesh {
+                flows.push((receiver.dc.clone(), &mut sender.rx));
+            }
+        }
+    }
+
+    // Every sender gets its own task so flows really compete; arrivals are
+    // timestamped as they come in, so they can be collected afterwards
+    let start = Instant::now();
+    let runs = async {
+        let senders = flows.iter().map(|(dc, _)| {
+            let dc = dc.clone();
+            tokio::spawn(async move { send_sequenced(&dc, iterations, msg_size).await })
+        });
+        let sent = try_join_all(senders).await?;
+        let mut runs = Vec::with_capacity(flows.len());
+        for ((_, receiver), sent) in flows.into_iter().zip(sent) {
+            runs.push(receive_sequenced(config, receiver, &sent?, start).await);
+        }
+        Result::<_>::Ok(runs)
+    }
+    .await;
+
+    // Close every connection, whether or not the run succeeded
+    let closed = close_connections(connections).await;
+    let runs = runs?;
+    closed?;
+
+    let mut histogram = LatencyHistogram::new();
+    let mut throughputs = Vec::with_capacity(runs.len());
+    for run in &runs {
+        histogram.merge(&run.histogram);
+        throughputs.push(if run.delivered > 0 {
+            run.total_bytes as f64 / run.elapsed.as_secs_f64() / 1_000_000.0
+        } else {
+            0.0
+        });
+    }
+    let elapsed = runs.iter().map(|run| run.elapsed).max().unwrap_or_default();
+    let total_bytes = runs.iter().map(|run| run.total_bytes).sum();
+    let delivered = runs.iter().map(|run| run.delivered).sum();
+
+    Ok(BenchResult::new(elapsed, total_bytes, &histogram)
+        .with_delivery(iterations * runs.len() as u64, delivered)
+        .with_fairness(Fairness::new(&throughputs)))
+}
+
+// Every benchmark message starts with its sequence number
+fn check_msg_size(msg_size: usize) -> Result<()> {
+    if msg_size < SEQ_LEN {
+        return Err(anyhow!("WebRTC messages need at least {} bytes, got {}", SEQ_LEN, msg_size));
+    }
+    Ok(())
+}
+
+// Closes both ends of every connection, returning the first error after trying them all
+async fn close_connections(connections: Vec<(Peer, Peer)>) -> Result<()> {
+    let mut result = Ok(());
+    for (offerer, answerer) in connections {
+        for peer in [offerer, answerer] {
+            if let Err(e) = peer.close().await {
+                result = result.and(Err(e));
+            }
+        }
+    }
+    result
+}
+
+struct Run {
+    elapsed: Duration,
+    total_bytes: usize,
+    histogram: LatencyHistogram,
+    delivered: u64,
+    first_arrival: Option<Instant>,
+}
+
+impl Run {
+    // The offerer's setup timings, plus the time from it starting to negotiate
+    // until the first benchmark message arrived
+    fn setup_with_first_byte(&self, offerer: &Peer) -> SetupTimings {
+        let mut setup = offerer.setup.clone();
+        setup.time_to_first_byte = self.first_arrival.map(|arrival| arrival - offerer.started);
+        setup
+    }
+}
+
+// Sends everything arriving on `channel` straight back, counting the messages echoed
+async fn echo(channel: &mut Channel, echoed: &mut u64) -> Result<()> {
+    while let Some((data, _)) = channel.rx.recv().await {
+        channel.dc.send(&data).await?;
+        *echoed += 1;
+    }
+    Ok(())
+}
+
+async fn round_trip(config: &WebRtcConfig, channel: &mut Channel, iterations: u64, msg_size: usize) -> Result<Run> {
+    let mut message = vec![0u8; msg_size];
+    let mut total_bytes = 0;
+    let mut delivered = 0;
+    let mut first_arrival = None;
+    let mut histogram = LatencyHistogram::with_capacity(iterations as usize);
+
+    let start = Instant::now();
+    for seq in 0..iterations {
+        message[..SEQ_LEN].copy_from_slice(&seq.to_be_bytes());
+        let sent = Instant::now();
+        channel.dc.send(&Bytes::copy_from_slice(&message)).await?;
+
+        // Wait for this message's echo, dropping late echoes of earlier ones
+        let deadline = config.loss_deadline(sent);
+        loop {
+            match recv_until(&mut channel.rx, deadline).await {
+                Ok(Some((reply, received))) if read_seq(&reply) == Some(seq) => {
+                    first_arrival.get_or_insert(received);
+                    histogram.record(sent.elapsed());
+                    total_bytes += msg_size + reply.len();
+                    delivered += 1;
+                    break;
+                }
+                Ok(Some(_)) => {}
+                Ok(None) => return Err(anyhow!("Data channel closed")),
+                Err(_) => break,
+            }
+        }
+    }
+
+    Ok(Run { elapsed: start.elapsed(), total_bytes, histogram, delivered, first_arrival })
+}
+
+async fn one_way(
+    config: &WebRtcConfig,
+    sender: &RTCDataChannel,
+    receiver: &mut mpsc::UnboundedReceiver<(Bytes, Instant)>,
+    iterations: u64,
+    msg_size: usize,
+) -> Result<Run> {
+    // Start benchmarking
+    let start = Instant::now();
+    let sent = send_sequenced(sender, iterations, msg_size).await?;
+    Ok(receive_sequenced(config, receiver, &sent, start).await)
+}
+
+// Sends `iterations` numbered messages back to back, returning when each was sent
+async fn send_sequenced(sender: &RTCDataChannel, iterations: u64, msg_size: usize) -> Result<Vec<Instant>> {
+    let mut message = vec![0u8; msg_size];
+    let mut sent = Vec::with_capacity(iterations as usize);
+    for seq in 0..iterations {
+        message[..SEQ_LEN].copy_from_slice(&seq.to_be_bytes());
+        sent.push(Instant::now());
+        sender.send(&Bytes::copy_from_slice(&message)).await?;
+    }
+    Ok(sent)
+}
+
+// Matches arrivals to the send times in `sent` by sequence number, since
+// unordered channels can deliver out of order. Stops once everything arrived or
+// the channel goes quiet. The run ends at the last arrival or the last send,
+// whichever is later, so it has a length even if nothing arrived.
+async fn receive_sequenced(
+    config: &WebRtcConfig,
+    receiver: &mut mpsc::UnboundedReceiver<(Bytes, Instant)>,
+    sent: &[Instant],
+    start: Instant,
+) -> Run {
+    let iterations = sent.len() as u64;
+    let mut seen = vec![false; sent.len()];
+    let mut total_bytes = 0;
+    let mut delivered = 0;
+    let mut first_arrival = None;
+    let mut end = sent.last().map_or(start, |&last| last.max(start));
+    let mut histogram = LatencyHistogram::with_capacity(iterations as usize);
+    while delivered < iterations {
+        let Ok(Some((data, received))) = recv_until(receiver, config.loss_deadline(Instant::now())).await else {
+            break;
+        };
+        let Some(seq) = read_seq(&data).filter(|&seq| seq < iterations && !seen[seq as usize]) else {
+            continue;
+        };
+        seen[seq as usize] = true;
+        histogram.record(received - sent[seq as usize]);
+        total_bytes += data.len();
+        delivered += 1;
+        first_arrival.get_or_insert(received);
+        end = end.max(received);
+    }
+
+    Run { elapsed: end - start, total_bytes, histogram, delivered, first_arrival }
+}
+
+// Waits for the next message until `deadline`, if there is one
+async fn recv_until<T>(
+    receiver: &mut mpsc::UnboundedReceiver<T>,
+    deadline: Option<tokio::time::Instant>,
+) -> Result<Option<T>, tokio::time::error::Elapsed> {
+    match deadline {
+        Some(deadline) => tokio::time::timeout_at(deadline, receiver.recv()).await,
+        None => Ok(receiver.recv().await),
+    }
+}
+
+/// How the two peers find a path to each other.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum IceMode {
+    /// Host candidates only and no ICE servers, so nothing leaves the machine.
+    /// webrtc-rs skips loopback interfaces, so this needs at least one other
+    /// local IPv4 address.
+    #[default]
+    HostOnly,
+    /// Adds server-reflexive candidates from an embedded [`LocalIceServer`].
+    LocalStun,
+    /// Relays all traffic through an embedded [`LocalIceServer`].
+    LocalTurn,
+}
+
+impl IceMode {
+    pub fn name(&self) -> &'static str {
+        match self {
+            IceMode::HostOnly => "host",
+            IceMode::LocalStun => "stun",
+            IceMode::LocalTurn => "turn",
+        }
+    }
+}
+
+impl FromStr for IceMode {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s.to_ascii_lowercase().as_str() {
+            "host" => Ok(IceMode::HostOnly),
+            "stun" => Ok(IceMode::LocalStun),
+            "turn" => Ok(IceMode::LocalTurn),
+            other => Err(anyhow!("Unknown ICE mode: {}", other)),
+        }
+    }
+}
+
+/// How hard the SCTP layer tries to deliver each data channel message.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum ChannelReliability {
+    /// Retransmit until delivered.
+    #[default]
+    Reliable,
+    /// Give up after this many retransmissions; 0 never retransmits.
+    MaxRetransmits(u16),
+    /// Give up once a message is this many milliseconds old.
+    MaxPacketLifeTime(u16),
+}
+
+/// When local ICE candidates are handed to the remote peer.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum IceGathering {
+    /// Wait for gathering to finish and send every candidate inside the
+    /// description, so each side sends a single message.
+    #[default]
+    Full,
+    /// Send the description straight away and each candidate as it is found,
+    /// so connectivity checks can start while gathering continues.
+    Trickle,
+}
+
+impl IceGathering {
+    pub fn name(&self) -> &'static str {
+        match self {
+            IceGathering::Full => "full",
+            IceGathering::Trickle => "trickle",
+        }
+    }
+}
+
+impl FromStr for IceGathering {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s.to_ascii_lowercase().as_str() {
+            "full" => Ok(IceGathering::Full),
+            "trickle" => Ok(IceGathering::Trickle),
+            other => Err(anyhow!("Unknown ICE gathering mode: {}", other)),
+        }
+    }
+}
+
+/// A simulated network between two in-process peers, built on
+/// `webrtc_util::vnet`. Applies to every packet, including ICE, DTLS and SCTP
+/// retransmissions.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct VirtualNetwork {
+    /// Minimum delay for every packet in each direction.
+    pub latency: Duration,
+    /// Up to this much extra delay. The router pauses for it between batches,
+    /// so packets queued together are delayed together.
+    pub jitter: Duration,
+    /// Fraction of packets dropped, from 0.0 to 1.0.
+    pub loss: f64,
+}
+
+/// WebRTC-specific settings.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct WebRtcConfig {
+    pub ice_mode: IceMode,
+    pub gathering: IceGathering,
+    /// Whether the data channel delivers messages in order.
+    pub ordered: bool,
+    pub reliability: ChannelReliability,
+    /// Data channels opened on each connection. Both peers must agree on it.
+    /// Benchmarks other than [`webrtc_fanout_benchmark`] only use the first.
+    pub channels: usize,
+    /// How long the benchmarks wait for a message before counting it as lost.
+    /// Only applies to unreliable channels.
+    pub recv_timeout: Duration,
+    /// Run in-process peers over a simulated network instead of loopback.
+    pub vnet: Option<VirtualNetwork>,
+}
+
+impl Default for WebRtcConfig {
+    fn default() -> Self {
+        Self {
+            ice_mode: IceMode::default(),
+            gathering: IceGathering::default(),
+            ordered: true,
+            reliability: ChannelReliability::default(),
+            channels: 1,
+            recv_timeout: Duration::from_secs(1),
+            vnet: None,
+        }
+    }
+}
+
+impl WebRtcConfig {
+    // When to stop waiting for a message sent at `sent`. Reliable channels
+    // deliver everything eventually, even if a retransmission timeout stalls
+    // them for longer than `recv_timeout`, so they never give up.
+    fn loss_deadline(&self, sent: Instant) -> Option<tokio::time::Instant> {
+        (self.reliability != ChannelReliability::Reliable)
+            .then(|| tokio::time::Instant::from_std(sent + self.recv_timeout))
+    }
+
+    fn data_channel_init(&self) -> RTCDataChannelInit {
+        let (max_retransmits, max_packet_life_time) = match self.reliability {
+            ChannelReliability::Reliable => (None, None),
+            ChannelReliability::MaxRetransmits(n) => (Some(n), None),
+            ChannelReliability::MaxPacketLifeTime(ms) => (None, Some(ms)),
+        };
+        RTCDataChannelInit {
+            ordered: Some(self.ordered),
+            max_retransmits,
+            max_packet_life_time,
+            ..Default::default()
+        }
+    }
+}
+
+/// Which side of the offer/answer exchange a peer plays.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum PeerRole {
+    /// Creates the data channel and sends the offer.
+    Offerer,
+    /// Waits for an offer and answers it.
+    Answerer,
+}
+
+/// A negotiated peer connection and its ends of the data channels.
+pub struct Peer {
+    pc: Arc<RTCPeerConnection>,
+    /// In the order the offerer created them.
+    channels: Vec<Channel>,
+    /// Kept alive for as long as the peer uses it.
+    ice_server: Option<LocalIceServer>,
+    /// The virtual network it runs over, if this peer is the one that stops it.
+    router: Option<Arc<Mutex<Router>>>,
+    setup: SetupTimings,
+    /// When negotiation started, for time-to-first-byte.
+    started: Instant,
+}
+
+// One data channel and every message received on it, with its arrival time
+struct Channel {
+    dc: Arc<RTCDataChannel>,
+    rx: mpsc::UnboundedReceiver<(Bytes, Instant)>,
+}
+
+impl Peer {
+    fn first_channel(&mut self) -> &mut Channel {
+        &mut self.channels[0]
+    }
+
+    /// How long each phase of negotiation took on this side.
+    pub fn setup(&self) -> &SetupTimings {
+        &self.setup
+    }
+
+    pub async fn close(self) -> Result<()> {
+        self.pc.close().await?;
+        if let Some(server) = self.ice_server {
+            server.close().await?;
+        }
+        if let Some(router) = self.router {
+            stop_virtual_network(&router).await?;
+        }
+        Ok(())
+    }
+}
+
+/// Creates a peer connection and negotiates it with a remote peer through
+/// `signaling`, returning once the data channel is open.
+///
+/// With [`IceGathering::Full`] each side gathers all of its candidates before
+/// sending its description, so a single message in each direction is enough;
+/// with [`IceGathering::Trickle`] candidates follow the description one by one.
+/// With a local STUN or TURN mode, each peer starts its own embedded server.
+///
+/// A [`VirtualNetwork`] needs both peers in this process, so `config.vnet` must
+/// be `None`.
+pub async fn connect_peer(role: PeerRole, config: &WebRtcConfig, signaling: &mut dyn Signaling) -> Result<Peer> {
+    if config.vnet.is_some() {
+        return Err(anyhow!("A virtual network only works with both WebRTC peers in this process"));
+    }
+    connect_peer_on(role, config, signaling, None).await
+}
+
+// Connects a peer as described for `connect_peer`, over `vnet` if given
+async fn connect_peer_on(
+    role: PeerRole,
+    config: &WebRtcConfig,
+    signaling: &mut dyn Signaling,
+    vnet: Option<Arc<Net>>,
+) -> Result<Peer> {
+    if config.channels == 0 {
+        return Err(anyhow!("A WebRTC connection needs at least one data channel"));
+    }
+
     // Initialize MediaEngine and Interceptors
     let mut m = MediaEngine::default();
     m.register_default_codecs()?;
@@ -25,211 +671,517 @@ pub async fn webrtc_benchmark(
     let mut registry = Registry::new();
     registry = register_default_interceptors(registry, &mut m)?;
 
+    // Every embedded server listens on IPv4 loopback
+    let mut settings = SettingEngine::default();
+    settings.set_network_types(vec![NetworkType::Udp4]);
+    if config.gathering == IceGathering::Trickle {
+        // Checks often arrive before the candidate they come from, making it
+        // peer-reflexive; by default those pairs aren't nominated for a second
+        settings.set_prflx_acceptance_min_wait(Some(Duration::ZERO));
+    }
+    if vnet.is_some() && config.ice_mode != IceMode::HostOnly {
+        return Err(anyhow!("A virtual network only supports host ICE candidates"));
+    }
+    settings.set_vnet(vnet);
+
     let api = APIBuilder::new()
         .with_media_engine(m)
         .with_interceptor_registry(registry)
+        .with_setting_engine(settings)
         .build();
 
-    // Configure the STUN server (use a public one for testing)
-    let config = RTCConfiguration {
-        ice_servers: vec![RTCIceServer {
-            // urls: vec!["stun:stun.l.google.com:19302".to_owned()],
-            urls: vec!["stun:localhost:3478".to_owned()],
+    let ice_server = match config.ice_mode {
+        IceMode::HostOnly => None,
+        IceMode::LocalStun | IceMode::LocalTurn => {
+            Some(LocalIceServer::start(SocketAddr::from((Ipv4Addr::LOCALHOST, 0))).await?)
+        }
+    };
+    let rtc_config = match (&ice_server, config.ice_mode) {
+        (Some(server), IceMode::LocalTurn) => RTCConfiguration {
+            ice_servers: vec![server.turn_server()],
+            ice_transport_policy: RTCIceTransportPolicy::Relay,
             ..Default::default()
-        }],
-        ..Default::default()
+        },
+        (Some(server), _) => RTCConfiguration { ice_servers: vec![server.stun_server()], ..Default::default() },
+        (None, _) => RTCConfiguration::default(),
     };
 
-    // Signaling channels for SDP and ICE candidates
-    let (sdp_tx1, mut sdp_rx1) = mpsc::channel::<String>(1);
-    let (sdp_tx2, mut sdp_rx2) = mpsc::channel::<String>(1);
-
-    let (ice_tx1, mut ice_rx1) = mpsc::channel::<String>(10);
-    let (ice_tx2, mut ice_rx2) = mpsc::channel::<String>(10);
-
-    // Create Peer Connections
-    let pc1 = Arc::new(api.new_peer_connection(config.clone()).await?);
-    let pc2 = Arc::new(api.new_peer_connection(config).await?);
-
-    // Set up ICE candidate exchange for pc1
-    {
-        let ice_tx1 = ice_tx1.clone();
-        pc1.on_ice_candidate(Box::new(move |candidate| {
-            let ice_tx1 = ice_tx1.clone();
-            Box::pin(async move {
-                if let Some(c) = candidate {
-                    let cand = serde_json::to_string(&c.to_json().unwrap()).unwrap();
-                    ice_tx1.send(cand).await.unwrap();
-                }
-            })
+    // Nothing started for a peer that fails to connect is left running
+    let negotiated = async {
+        let pc = Arc::new(api.new_peer_connection(rtc_config).await?);
+        match negotiate(role, config, signaling, &pc).await {
+            Ok((channels, setup, started)) => Ok((pc, channels, setup, started)),
+            Err(e) => {
+                let _ = pc.close().await;
+                Err(e)
+            }
+        }
+    }
+    .await;
+    match negotiated {
+        Ok((pc, channels, setup, started)) => Ok(Peer { pc, channels, ice_server, router: None, setup, started }),
+        Err(e) => {
+            if let Some(server) = ice_server {
+                let _ = server.close().await;
+            }
+            Err(e)
+        }
+    }
+}
+
+// Negotiates `pc` with the remote peer as described for `connect_peer`. Returns
+// the open data channels, setup timings and when negotiation started.
+async fn negotiate(
+    role: PeerRole,
+    config: &WebRtcConfig,
+    signaling: &mut dyn Signaling,
+    pc: &Arc<RTCPeerConnection>,
+) -> Result<(Vec<Channel>, SetupTimings, Instant)> {
+    // Watch for state changes before negotiating, so no transition can be missed
+    let ice_connected = on_ice_connected(pc);
+    let connected = on_connected(pc);
+
+    // The offerer creates the data channels; the answerer is handed them once negotiated.
+    // Incoming messages are queued from the start, so none arrive before anyone listens.
+    let (dc_tx, mut dc_rx) = mpsc::unbounded_channel();
+    match role {
+        PeerRole::Offerer => {
+            for index in 0..config.channels {
+                let dc = pc.create_data_channel(&format!("data-{}", index), Some(config.data_channel_init())).await?;
+                dc_tx.send(Channel::new(dc))?;
+            }
+        }
+        PeerRole::Answerer => {
+            // webrtc-rs only starts reading the channel once this handler returns
+            pc.on_data_channel(Box::new(move |dc| {
+                let _ = dc_tx.send(Channel::new(dc));
+                Box::pin(async {})
+            }));
+        }
+    }
+
+    // Local candidates, then None once gathering is done
+    let (candidate_tx, mut candidate_rx) = mpsc::unbounded_channel();
+    if config.gathering == IceGathering::Trickle {
+        pc.on_ice_candidate(Box::new(move |candidate| {
+            let _ = candidate_tx.send(candidate);
+            Box::pin(async {})
         }));
     }
 
-    // Set up ICE candidate exchange for pc2
-    {
-        let ice_tx2 = ice_tx2.clone();
-        pc2.on_ice_candidate(Box::new(move |candidate| {
-            let ice_tx2 = ice_tx2.clone();
-            Box::pin(async move {
-                if let Some(c) = candidate {
-                    let cand = serde_json::to_string(&c.to_json().unwrap()).unwrap();
-                    ice_tx2.send(cand).await.unwrap();
+    // SDP Offer/Answer exchange. Trickled candidates keep flowing while the
+    // connection comes up, so it can open before gathering finishes.
+    let signaling_start = Instant::now();
+    let mut signaled = None;
+    let (ice_connected, connected, channels, opened) = {
+        let exchange = async {
+            match (role, config.gathering) {
+                (PeerRole::Offerer, IceGathering::Full) => {
+                    let offer = pc.create_offer(None).await?;
+                    send_description(pc, offer, signaling).await?;
+                    pc.set_remote_description(recv_description(signaling).await?).await?;
+                    signaled = Some(Instant::now());
+                }
+                (PeerRole::Answerer, IceGathering::Full) => {
+                    pc.set_remote_description(recv_description(signaling).await?).await?;
+                    let answer = pc.create_answer(None).await?;
+                    send_description(pc, answer, signaling).await?;
+                    signaled = Some(Instant::now());
+                }
+                (PeerRole::Offerer, IceGathering::Trickle) => {
+                    let offer = pc.create_offer(None).await?;
+                    pc.set_local_description(offer.clone()).await?;
+                    signaling.send(&SignalMessage::Description { description: Box::new(offer) }).await?;
+                    trickle(pc, signaling, &mut candidate_rx, &mut signaled).await?;
+                }
+                (PeerRole::Answerer, IceGathering::Trickle) => {
+                    pc.set_remote_description(recv_description(signaling).await?).await?;
+                    signaled = Some(Instant::now());
+                    let answer = pc.create_answer(None).await?;
+                    pc.set_local_description(answer.clone()).await?;
+                    signaling.send(&SignalMessage::Description { description: Box::new(answer) }).await?;
+                    trickle(pc, signaling, &mut candidate_rx, &mut signaled).await?;
                 }
-            })
+            }
+            Result::<()>::Ok(())
+        };
+        let established = wait_for_connection(ice_connected, connected, &mut dc_rx, config.channels);
+        tokio::pin!(exchange, established);
+
+        // Once connected there is nothing left to exchange
+        tokio::select! {
+            result = &mut established => result?,
+            result = &mut exchange => {
+                result?;
+                established.await?
+            }
+        }
+    };
+    let signaled = signaled.ok_or_else(|| anyhow!("No remote description received"))?;
+
+    // ICE checks can start before the remote description is applied, so phases saturate at zero
+    let mut setup = SetupTimings::new();
+    setup.push("signaling", signaled - signaling_start);
+    setup.push("ice_connected", ice_connected.saturating_duration_since(signaled));
+    setup.push("dtls", connected.saturating_duration_since(ice_connected.max(signaled)));
+    setup.push("data_channel_open", opened.saturating_duration_since(connected.max(signaled)));
+
+    Ok((channels, setup, signaling_start))
+}
+
+// Waits for ICE, then DTLS on top of it, then for `channels` data channels to
+// open. Returns when each of those finished, and the channels in creation order.
+async fn wait_for_connection(
+    ice_connected: oneshot::Receiver<Option<Instant>>,
+    connected: oneshot::Receiver<Option<Instant>>,
+    dc_rx: &mut mpsc::UnboundedReceiver<Channel>,
+    channels: usize,
+) -> Result<(Instant, Instant, Vec<Channel>, Instant)> {
+    let ice_connected = ice_connected.await?.ok_or_else(|| anyhow!("ICE did not connect"))?;
+    let connected = connected
+        .await?
+        .ok_or_else(|| anyhow!("Peer connection did not reach connected state"))?;
+
+    let mut opened = Vec::with_capacity(channels);
+    while opened.len() < channels {
+        let channel = dc_rx.recv().await.ok_or_else(|| anyhow!("No data channel arrived"))?;
+        if !wait_for_data_channel_open(channel.dc.clone()).await? {
+            return Err(anyhow!("Data channel {} did not open", channel.dc.label()));
+        }
+        opened.push(channel);
+    }
+    opened.sort_by_key(|channel| channel.index());
+    Ok((ice_connected, connected, opened, Instant::now()))
+}
+
+impl Channel {
+    // Starts queueing messages from `dc` straight away
+    fn new(dc: Arc<RTCDataChannel>) -> Self {
+        let (tx, rx) = mpsc::unbounded_channel();
+        dc.on_message(Box::new(move |msg: DataChannelMessage| {
+            let _ = tx.send((msg.data, Instant::now()));
+            Box::pin(async {})
         }));
+        Self { dc, rx }
     }
 
-    // Create data channel only on pc1
-    let dc = pc1.create_data_channel("data", None).await?;
+    // Position the offerer created this channel at, from its `data-N` label
+    fn index(&self) -> usize {
+        self.dc.label().strip_prefix("data-").and_then(|index| index.parse().ok()).unwrap_or(0)
+    }
+}
 
-    // Set up data channel on pc2 when it's created by pc1
-    let (dc2_tx, mut dc2_rx) = mpsc::channel(1);
-    pc2.on_data_channel(Box::new(move |dc| {
-        let dc2_tx = dc2_tx.clone();
-        Box::pin(async move {
-            dc2_tx.send(dc).await.unwrap();
-        })
-    }));
+/// Connects an offerer and an answerer within this process, returning them in
+/// that order.
+async fn connect_peers(config: &WebRtcConfig) -> Result<(Peer, Peer)> {
+    let (router, offerer_net, answerer_net) = match &config.vnet {
+        Some(vnet) => {
+            let (router, offerer_net, answerer_net) = start_virtual_network(vnet).await?;
+            (Some(router), Some(offerer_net), Some(answerer_net))
+        }
+        None => (None, None, None),
+    };
 
-    // SDP Offer/Answer exchange
-    // Create offer on pc1
-    let offer = pc1.create_offer(None).await?;
-    pc1.set_local_description(offer.clone()).await?;
+    let (mut offerer, mut answerer) = InProcessSignaling::pair();
+    let (offerer, answerer) = tokio::join!(
+        connect_peer_on(PeerRole::Offerer, config, &mut offerer, offerer_net),
+        connect_peer_on(PeerRole::Answerer, config, &mut answerer, answerer_net),
+    );
+    match (offerer, answerer) {
+        (Ok(mut offerer), Ok(answerer)) => {
+            offerer.router = router;
+            Ok((offerer, answerer))
+        }
+        // Report why connecting failed rather than any trouble shutting down
+        (offerer, answerer) => {
+            let mut error = None;
+            for peer in [offerer, answerer] {
+                match peer {
+                    Ok(peer) => {
+                        let _ = peer.close().await;
+                    }
+                    Err(e) => {
+                        error.get_or_insert(e);
+                    }
+                }
+            }
+            if let Some(router) = router {
+                let _ = stop_virtual_network(&router).await;
+            }
+            Err(error.unwrap())
+        }
+    }
+}
 
-    // Wait for ICE gathering to complete on pc1
-    let mut pc1_ice_gathering_complete = pc1.gathering_complete_promise().await;
-    pc1_ice_gathering_complete.recv().await;
+// Starts a virtual router for `vnet`, returning it and a network for each peer on it
+async fn start_virtual_network(vnet: &VirtualNetwork) -> Result<(Arc<Mutex<Router>>, Arc<Net>, Arc<Net>)> {
+    if !(0.0..=1.0).contains(&vnet.loss) {
+        return Err(anyhow!("loss must be between 0 and 1, got {}", vnet.loss));
+    }
+    let router = Arc::new(Mutex::new(Router::new(RouterConfig {
+        cidr: "10.0.0.0/24".to_owned(),
+        min_delay: vnet.latency,
+        max_jitter: vnet.jitter,
+        ..Default::default()
+    })?));
+    let loss = vnet.loss;
+    router
+        .lock()
+        .await
+        .add_chunk_filter(Box::new(move |_| loss == 0.0 || rand::random::<f64>() >= loss))
+        .await;
 
-    let offer_sdp = serde_json::to_string(&pc1.local_description().await.unwrap())?;
-    sdp_tx1.send(offer_sdp).await?;
+    let mut nets = Vec::new();
+    for ip in ["10.0.0.1", "10.0.0.2"] {
+        let net = Arc::new(Net::new(Some(NetConfig { static_ips: vec![ip.to_owned()], ..Default::default() })));
+        let nic = net.get_nic()?;
+        router.lock().await.add_net(nic.clone()).await?;
+        nic.lock().await.set_router(router.clone()).await?;
+        nets.push(net);
+    }
 
-    // pc2 receives the offer
-    let offer_sdp = sdp_rx1.recv().await.unwrap();
-    let offer = serde_json::from_str::<RTCSessionDescription>(&offer_sdp)?;
-    pc2.set_remote_description(offer).await?;
+    // Starting only returns a future to start child routers, and there are none
+    router.lock().await.start().now_or_never().ok_or_else(|| anyhow!("Virtual router did not start"))??;
 
-    // Create answer on pc2
-    let answer = pc2.create_answer(None).await?;
-    pc2.set_local_description(answer.clone()).await?;
+    let answerer = nets.pop().unwrap();
+    let offerer = nets.pop().unwrap();
+    Ok((router, offerer, answerer))
+}
 
-    // Wait for ICE gathering to complete on pc2
-    let mut pc2_ice_gathering_complete = pc2.gathering_complete_promise().await;
-    pc2_ice_gathering_complete.recv().await;
+async fn stop_virtual_network(router: &Mutex<Router>) -> Result<()> {
+    // Stopping only returns a future to stop child routers, and there are none
+    router.lock().await.stop().now_or_never().ok_or_else(|| anyhow!("Virtual router did not stop"))??;
+    Ok(())
+}
 
-    let answer_sdp = serde_json::to_string(&pc2.local_description().await.unwrap())?;
-    sdp_tx2.send(answer_sdp).await?;
+// Applies `description` locally and sends it once ICE gathering has finished,
+// so it carries every local candidate
+async fn send_description(
+    pc: &RTCPeerConnection,
+    description: RTCSessionDescription,
+    signaling: &mut dyn Signaling,
+) -> Result<()> {
+    let mut gathering_complete = pc.gathering_complete_promise().await;
+    pc.set_local_description(description).await?;
+    gathering_complete.recv().await;
 
-    // pc1 receives the answer
-    let answer_sdp = sdp_rx2.recv().await.unwrap();
-    let answer = serde_json::from_str::<RTCSessionDescription>(&answer_sdp)?;
-    pc1.set_remote_description(answer).await?;
+    let description = pc
+        .local_description()
+        .await
+        .ok_or_else(|| anyhow!("No local description after gathering"))?;
+    signaling.send(&SignalMessage::Description { description: Box::new(description) }).await
+}
 
-    // Start exchanging ICE candidates
-    let pc1_clone = pc1.clone();
-    let pc2_clone = pc2.clone();
+async fn recv_description(signaling: &mut dyn Signaling) -> Result<RTCSessionDescription> {
+    match signaling.recv().await? {
+        SignalMessage::Description { description } => Ok(*description),
+        other => Err(anyhow!("Expected a session description, got {:?}", other)),
+    }
+}
 
-    let ice_exchange = async move {
-        loop {
-            tokio::select! {
-                Some(ice) = ice_rx1.recv() => {
-                    let candidate = serde_json::from_str::<RTCIceCandidateInit>(&ice)?;
-                    pc2_clone.add_ice_candidate(candidate).await?;
-                },
-                Some(ice) = ice_rx2.recv() => {
-                    let candidate = serde_json::from_str::<RTCIceCandidateInit>(&ice)?;
-                    pc1_clone.add_ice_candidate(candidate).await?;
-                },
-                else => break,
-            }
-        }
-        Result::<(), anyhow::Error>::Ok(())
-    };
+// Sends local candidates as they are gathered and applies remote ones as they
+// arrive, until both sides are done. Remote candidates that arrive before the
+// remote description are held back. `signaled` is when the remote description
+// was applied, set here if it arrives during the exchange.
+async fn trickle(
+    pc: &RTCPeerConnection,
+    signaling: &mut dyn Signaling,
+    candidates: &mut mpsc::UnboundedReceiver<Option<RTCIceCandidate>>,
+    signaled: &mut Option<Instant>,
+) -> Result<()> {
+    enum Event {
+        Local(Option<RTCIceCandidate>),
+        Remote(SignalMessage),
+    }
 
-    // Start ICE exchange
-    tokio::spawn(async move {
-        if let Err(e) = ice_exchange.await {
-            eprintln!("ICE exchange error: {:?}", e);
+    let mut local_done = false;
+    let mut remote_done = false;
+    let mut pending = Vec::new();
+    loop {
+        let remote_pending = !remote_done || signaled.is_none();
+        if local_done && !remote_pending {
+            break;
         }
-    });
-
-    // Wait for the peer connections to reach connected state
-    let pc1_connected = wait_for_peer_connection(pc1.clone()).await?;
-    let pc2_connected = wait_for_peer_connection(pc2.clone()).await?;
 
-    if !pc1_connected || !pc2_connected {
-        return Err(anyhow::anyhow!("Peer connections did not reach connected state"));
+        let event = tokio::select! {
+            candidate = candidates.recv(), if !local_done => Event::Local(candidate.flatten()),
+            msg = signaling.recv(), if remote_pending => Event::Remote(msg?),
+        };
+        match event {
+            Event::Local(Some(candidate)) => {
+                let candidate = candidate.to_json()?;
+                signaling.send(&SignalMessage::Candidate { candidate }).await?;
+            }
+            Event::Local(None) => {
+                local_done = true;
+                signaling.send(&SignalMessage::EndOfCandidates).await?;
+            }
+            Event::Remote(SignalMessage::Description { description }) => {
+                pc.set_remote_description(*description).await?;
+                *signaled = Some(Instant::now());
+                for candidate in pending.drain(..) {
+                    pc.add_ice_candidate(candidate).await?;
+                }
+            }
+            Event::Remote(SignalMessage::Candidate { candidate }) if signaled.is_some() => {
+                pc.add_ice_candidate(candidate).await?;
+            }
+            Event::Remote(SignalMessage::Candidate { candidate }) => pending.push(candidate),
+            Event::Remote(SignalMessage::EndOfCandidates) => remote_done = true,
+        }
     }
+    Ok(())
+}
 
-    // Wait for data channels to open
-    let dc1_ready = wait_for_data_channel_open(dc.clone()).await?;
-    let dc2 = dc2_rx.recv().await.unwrap();
-    let dc2_ready = wait_for_data_channel_open(dc2.clone()).await?;
+/// One end of an in-process WebRTC data channel.
+pub struct WebRtcTransport {
+    peer: Peer,
+    /// Only the client end reports setup timings.
+    is_client: bool,
+}
 
-    if !dc1_ready || !dc2_ready {
-        return Err(anyhow::anyhow!("Data channels did not open"));
+impl WebRtcTransport {
+    fn new(peer: Peer, is_client: bool) -> Self {
+        Self { peer, is_client }
     }
+}
 
-    // Data receiving on dc2
-    let (data_rx_tx, mut data_rx_rx) = mpsc::channel::<usize>(iterations as usize);
-    dc2.on_message(Box::new(move |msg: DataChannelMessage| {
-        let len = msg.data.len();
-        let data_rx_tx = data_rx_tx.clone();
-        Box::pin(async move {
-            data_rx_tx.send(len).await.unwrap();
-        })
-    }));
-
-    // Start benchmarking
-    let start = Instant::now();
-    let message = Bytes::from(vec![0u8; msg_size]);
+#[async_trait]
+impl Transport for WebRtcTransport {
+    const NAME: &'static str = "WebRTC";
 
-    for _ in 0..iterations {
-        dc.send(&message).await?;
+    async fn connect(config: &TransportConfig) -> Result<(Self, Self)> {
+        let (offerer, answerer) = connect_peers(&config.webrtc).await?;
+        Ok((Self::new(offerer, true), Self::new(answerer, false)))
     }
 
-    // Collect received data sizes
-    let mut total_bytes = 0;
-    for _ in 0..iterations {
-        if let Some(len) = data_rx_rx.recv().await {
-            total_bytes += len;
-        }
+    async fn send(&mut self, msg: &[u8]) -> Result<()> {
+        self.peer.channels[0].dc.send(&Bytes::copy_from_slice(msg)).await?;
+        Ok(())
     }
 
-    let elapsed = start.elapsed();
+    async fn recv(&mut self) -> Result<Vec<u8>> {
+        let (data, _) = self.peer.first_channel().rx.recv().await.ok_or_else(|| anyhow!("Data channel closed"))?;
+        Ok(data.to_vec())
+    }
 
-    // Close peer connections
-    pc1.close().await?;
-    pc2.close().await?;
+    async fn close(self) -> Result<()> {
+        self.peer.close().await
+    }
 
-    Ok((elapsed, total_bytes))
+    fn setup_timings(&self) -> Option<SetupTimings> {
+        self.is_client.then(|| self.peer.setup.clone())
+    }
 }
 
-// Helper function to wait for the peer connection to reach connected state
-async fn wait_for_peer_connection(pc: Arc<webrtc::peer_connection::RTCPeerConnection>) -> Result<bool> {
-    let (connected_tx, connected_rx) = tokio::sync::oneshot::channel();
+// Resolves to when `pc` connected (ICE and DTLS both done), or None if it failed first
+fn on_connected(pc: &RTCPeerConnection) -> oneshot::Receiver<Option<Instant>> {
+    let (connected_tx, connected_rx) = oneshot::channel();
     let connected_tx = Arc::new(std::sync::Mutex::new(Some(connected_tx)));
     pc.on_peer_connection_state_change(Box::new(move |state| {
-        let connected_tx = connected_tx.clone();
-        Box::pin(async move {
-            let mut tx_guard = connected_tx.lock().unwrap();
-            if let Some(tx) = tx_guard.take() {
-                if state == RTCPeerConnectionState::Connected {
-                    let _ = tx.send(true);
-                } else if state == RTCPeerConnectionState::Failed {
-                    let _ = tx.send(false);
-                }
+        let connected = match state {
+            RTCPeerConnectionState::Connected => Some(Some(Instant::now())),
+            RTCPeerConnectionState::Failed | RTCPeerConnectionState::Closed => Some(None),
+            _ => None,
+        };
+        if let Some(connected) = connected {
+            if let Some(tx) = connected_tx.lock().unwrap().take() {
+                let _ = tx.send(connected);
             }
-        })
+        }
+        Box::pin(async {})
     }));
-    Ok(connected_rx.await?)
+    connected_rx
+}
+
+// Resolves to when ICE on `pc` found a working pair, or None if it failed first
+fn on_ice_connected(pc: &RTCPeerConnection) -> oneshot::Receiver<Option<Instant>> {
+    let (connected_tx, connected_rx) = oneshot::channel();
+    let connected_tx = Arc::new(std::sync::Mutex::new(Some(connected_tx)));
+    pc.on_ice_connection_state_change(Box::new(move |state| {
+        let connected = match state {
+            RTCIceConnectionState::Connected => Some(Some(Instant::now())),
+            RTCIceConnectionState::Failed | RTCIceConnectionState::Closed => Some(None),
+            _ => None,
+        };
+        if let Some(connected) = connected {
+            if let Some(tx) = connected_tx.lock().unwrap().take() {
+                let _ = tx.send(connected);
+            }
+        }
+        Box::pin(async {})
+    }));
+    connected_rx
 }
 
 // Helper function to wait for the data channel to open
-async fn wait_for_data_channel_open(dc: Arc<webrtc::data_channel::RTCDataChannel>) -> Result<bool> {
-    let (open_tx, open_rx) = tokio::sync::oneshot::channel();
+async fn wait_for_data_channel_open(dc: Arc<RTCDataChannel>) -> Result<bool> {
+    let (open_tx, open_rx) = oneshot::channel();
     dc.on_open(Box::new(move || {
         let _ = open_tx.send(true);
         Box::pin(async {})
     }));
     Ok(open_rx.await?)
 }
+
+#[tokio::test]
+async fn test_webrtc_benchmark() -> Result<()> {
+    use crate::transport::ping_pong;
+
+    for ice_mode in [IceMode::HostOnly, IceMode::LocalStun, IceMode::LocalTurn] {
+        let webrtc = WebRtcConfig { ice_mode, ..Default::default() };
+        let config = TransportConfig { webrtc, ..Default::default() };
+        let result = ping_pong::<WebRtcTransport>(&config, 10, 64).await?;
+        assert_eq!(result.total_bytes, 10 * 64 * 2, "{} ICE", ice_mode.name());
+    }
+
+    // 5 ms each way over a virtual network
+    let vnet = VirtualNetwork { latency: Duration::from_millis(5), ..Default::default() };
+    let config = WebRtcConfig { vnet: Some(vnet), ..Default::default() };
+    let result = webrtc_benchmark(&config, WebRtcBenchMode::RoundTrip, 10, 64).await?;
+    assert_eq!(result.total_bytes, 10 * 64 * 2);
+    assert!(result.latency.min >= Duration::from_millis(10), "{}", result.latency);
+    let config = WebRtcConfig { vnet: Some(VirtualNetwork { loss: 1.5, ..vnet }), ..Default::default() };
+    assert!(webrtc_benchmark(&config, WebRtcBenchMode::RoundTrip, 10, 64).await.is_err());
+
+    for gathering in [IceGathering::Full, IceGathering::Trickle] {
+        let config = WebRtcConfig { gathering, ..Default::default() };
+        let result = webrtc_benchmark(&config, WebRtcBenchMode::RoundTrip, 10, 64).await?;
+        assert_eq!(result.total_bytes, 10 * 64 * 2);
+        assert!(result.setup.unwrap().time_to_first_byte.is_some(), "{} gathering", gathering.name());
+        let result = webrtc_benchmark(&config, WebRtcBenchMode::OneWay, 10, 64).await?;
+        assert_eq!(result.total_bytes, 10 * 64);
+    }
+
+    let config = WebRtcConfig { channels: 2, ..Default::default() };
+    for topology in [WebRtcTopology::Star, WebRtcTopology::Mesh] {
+        let result = webrtc_fanout_benchmark(&config, topology, 3, 10, 64).await?;
+        // A star of 3 has 2 links sending one way, a mesh of 3 has 3 links sending both ways
+        let flows = if topology == WebRtcTopology::Star { 2 * 2 } else { 3 * 2 * 2 };
+        assert_eq!(result.fairness.map(|f| f.flows), Some(flows), "{}", topology.name());
+        assert_eq!(result.total_bytes, flows * 10 * 64);
+    }
+
+    // Reliable channels deliver everything; unreliable ones may give up on a
+    // message, but not on all of them over loopback
+    for reliability in [
+        ChannelReliability::Reliable,
+        ChannelReliability::MaxRetransmits(0),
+        ChannelReliability::MaxPacketLifeTime(50),
+    ] {
+        let config = WebRtcConfig { ordered: false, reliability, ..Default::default() };
+        for mode in [WebRtcBenchMode::RoundTrip, WebRtcBenchMode::OneWay] {
+            let result = webrtc_benchmark(&config, mode, 10, 64).await?;
+            let delivery = result.delivery.unwrap();
+            assert_eq!(delivery.sent, 10);
+            if reliability == ChannelReliability::Reliable {
+                assert_eq!(delivery.delivered, 10, "{:?}", mode);
+            } else {
+                assert!(delivery.delivered > 0, "{:?} {:?}", reliability, mode);
+            }
+            assert!(result.throughput_mbps().is_finite());
+        }
+    }
+
+    Ok(())
+}
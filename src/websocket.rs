@@ -1,37 +1,239 @@
+use std::net::SocketAddr;
 use std::time::Instant;
-use tokio::net::TcpListener;
-use tokio_tungstenite::{accept_async, tungstenite::Message};
+use anyhow::{anyhow, Result};
+use async_trait::async_trait;
+use tokio::io::{AsyncRead, AsyncWrite};
+use tokio::net::TcpStream;
+use tokio::sync::{mpsc, Semaphore};
+use tokio_tungstenite::{accept_async, client_async, connect_async, tungstenite::Message, WebSocketStream};
 use futures_util::{SinkExt, StreamExt};
-use tokio::task;
 
-pub async fn websocket_benchmark(iterations: usize, msg_size: usize, print_interval: usize) -> Result<(std::time::Duration, usize), Box<dyn std::error::Error>> {
-    let addr = "127.0.0.1:9002";
-    let listener = TcpListener::bind(&addr).await?;
-    println!("\nWebSocket server listening on: {}", addr);
+use crate::stats::{BenchResult, LatencyHistogram, SetupTimings};
+use crate::transport::{bind_tcp, connect_tcp, Transport, TransportConfig};
+
+/// Who answers the server in [`websocket_benchmark`].
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum WebSocketClientMode {
+    /// Spawn an echo client in this process, bound to `config.client_addr`.
+    #[default]
+    Local,
+    /// Wait for an external client, e.g. [`websocket_echo_client`] on another host.
+    Remote,
+}
+
+/// WebSocket server bound to `config.server_addr` that sends `iterations` binary
+/// messages to the first client to connect and waits for their echoes, with up
+/// to `config.window` messages outstanding at once.
+///
+/// Progress is printed to stderr every `print_interval` messages, leaving stdout
+/// to the report. A `print_interval` of 0 disables progress output.
+pub async fn websocket_benchmark(
+    config: &TransportConfig,
+    mode: WebSocketClientMode,
+    iterations: usize,
+    msg_size: usize,
+    print_interval: usize,
+) -> Result<BenchResult> {
+    if config.window == 0 {
+        return Err(anyhow!("window must be at least 1"));
+    }
+    let listener = bind_tcp(config.server_addr).await?;
+    let addr = listener.local_addr()?;
+    eprintln!("\nWebSocket server listening on: {}", addr);
+
+    let client = match mode {
+        WebSocketClientMode::Local => {
+            let local = config.client_addr;
+            Some(tokio::spawn(async move {
+                let (ws_stream, setup) = connect_websocket(local, addr).await?;
+                echo(ws_stream).await?;
+                Result::<_>::Ok(setup)
+            }))
+        }
+        WebSocketClientMode::Remote => None,
+    };
 
     let msg = vec![0; msg_size];
 
     let (stream, _) = listener.accept().await?;
+    let upgrade = Instant::now();
     let ws_stream = accept_async(stream).await?;
+    let upgrade = upgrade.elapsed();
     let (mut write, mut read) = ws_stream.split();
 
+    let mut histogram = LatencyHistogram::with_capacity(iterations);
     let start = Instant::now();
-    for i in 0..iterations {
-        write.send(Message::Binary(msg.clone())).await?;
-        let _ = read.next().await.ok_or("Failed to receive message")??;
-        
-        if i % print_interval == 0 {
-            let elapsed = start.elapsed();
-            let latency_ms = elapsed.as_nanos() as f64 / 1_000_000.0 / (i + 1) as f64;
-            let throughput_mbps = msg_size as f64 * (i + 1) as f64 / elapsed.as_secs_f64() / 1_000_000.0;
-            println!(
-                "Iteration {}: Latency: {:.2} ms/iter, Throughput: {:.2} MB/s",
-                i, latency_ms, throughput_mbps
-            );
+
+    // Send and receive concurrently, so a large window can't fill the socket
+    // buffers both ways and leave each end blocked on sending
+    let window = Semaphore::new(config.window);
+    let (sent_tx, mut sent_rx) = mpsc::unbounded_channel();
+    let send_all = async {
+        for _ in 0..iterations {
+            window.acquire().await?.forget();
+            sent_tx.send(Instant::now())?;
+            write.send(Message::Binary(msg.clone())).await?;
         }
-    }
+        Result::<_>::Ok(())
+    };
+    let receive_all = async {
+        for i in 0..iterations {
+            let _ = read.next().await.ok_or_else(|| anyhow!("Failed to receive message"))??;
+            // Echoes come back in order over TCP
+            let sent = sent_rx.recv().await.ok_or_else(|| anyhow!("Echo without a message"))?;
+            histogram.record(sent.elapsed());
+            window.add_permits(1);
+
+            if print_interval > 0 && i % print_interval == 0 {
+                let elapsed = start.elapsed();
+                let latency_ms = elapsed.as_nanos() as f64 / 1_000_000.0 / (i + 1) as f64;
+                let throughput_mbps = msg_size as f64 * (i + 1) as f64 / elapsed.as_secs_f64() / 1_000_000.0;
+                eprintln!(
+                    "Iteration {}: Latency: {:.2} ms/iter, Throughput: {:.2} MB/s",
+                    i, latency_ms, throughput_mbps
+                );
+            }
+        }
+        Result::<_>::Ok(())
+    };
+    tokio::try_join!(send_all, receive_all)?;
     let elapsed = start.elapsed();
     let total_bytes = msg_size * iterations * 2; // Account for both send and receive
 
-    Ok((elapsed, total_bytes))
+    write.close().await?;
+    // A local client times the whole handshake; for a remote one only the
+    // server's side of the upgrade is visible
+    let setup = match client {
+        Some(client) => client.await??,
+        None => {
+            let mut setup = SetupTimings::new();
+            setup.push("ws_upgrade", upgrade);
+            setup
+        }
+    };
+
+    Ok(BenchResult::new(elapsed, total_bytes, &histogram).with_server_addr(addr).with_setup(setup))
+}
+
+/// Connects to a [`websocket_benchmark`] server at `url` (e.g. `ws://host:port`)
+/// and echoes every message until the server closes the connection. Returns the
+/// number of messages echoed.
+pub async fn websocket_echo_client(url: &str) -> Result<u64> {
+    let (ws_stream, _) = connect_async(url).await?;
+    echo(ws_stream).await
+}
+
+// Connects from `local` to the server at `addr`, timing the TCP connect and the
+// WebSocket upgrade separately
+async fn connect_websocket(local: SocketAddr, addr: SocketAddr) -> Result<(WebSocketStream<TcpStream>, SetupTimings)> {
+    let mut setup = SetupTimings::new();
+    let start = Instant::now();
+    let stream = connect_tcp(local, addr).await?;
+    setup.push("tcp_connect", start.elapsed());
+
+    let start = Instant::now();
+    let (ws_stream, _) = client_async(format!("ws://{}", addr), stream).await?;
+    setup.push("ws_upgrade", start.elapsed());
+
+    Ok((ws_stream, setup))
+}
+
+// Sends every data message straight back until the peer closes
+async fn echo<S: AsyncRead + AsyncWrite + Unpin>(mut stream: WebSocketStream<S>) -> Result<u64> {
+    let mut echoed = 0;
+    while let Some(msg) = stream.next().await {
+        match msg? {
+            msg @ (Message::Binary(_) | Message::Text(_)) => {
+                stream.send(msg).await?;
+                echoed += 1;
+            }
+            Message::Close(_) => break,
+            _ => {}
+        }
+    }
+    Ok(echoed)
+}
+
+/// WebSocket connection carrying one binary frame per message.
+pub struct WebSocketTransport {
+    stream: WebSocketStream<TcpStream>,
+    /// Set on the client end only.
+    setup: Option<SetupTimings>,
+}
+
+#[async_trait]
+impl Transport for WebSocketTransport {
+    const NAME: &'static str = "WebSocket";
+
+    async fn connect(config: &TransportConfig) -> Result<(Self, Self)> {
+        let listener = bind_tcp(config.server_addr).await?;
+        let addr = listener.local_addr()?;
+
+        let server = async {
+            let (stream, _) = listener.accept().await?;
+            Result::<_>::Ok(accept_async(stream).await?)
+        };
+        let (server, (client, setup)) = tokio::try_join!(server, connect_websocket(config.client_addr, addr))?;
+
+        Ok((Self { stream: client, setup: Some(setup) }, Self { stream: server, setup: None }))
+    }
+
+    async fn send(&mut self, msg: &[u8]) -> Result<()> {
+        self.stream.send(Message::Binary(msg.to_vec())).await?;
+        Ok(())
+    }
+
+    async fn recv(&mut self) -> Result<Vec<u8>> {
+        loop {
+            match self.stream.next().await {
+                Some(Ok(Message::Binary(data))) => return Ok(data),
+                Some(Ok(Message::Text(text))) => return Ok(text.into_bytes()),
+                Some(Ok(Message::Close(_))) | None => return Err(anyhow!("WebSocket connection closed")),
+                Some(Ok(_)) => {}
+                Some(Err(e)) => return Err(e.into()),
+            }
+        }
+    }
+
+    async fn close(mut self) -> Result<()> {
+        self.stream.close(None).await?;
+        Ok(())
+    }
+
+    fn local_addr(&self) -> Option<SocketAddr> {
+        self.stream.get_ref().local_addr().ok()
+    }
+
+    fn setup_timings(&self) -> Option<SetupTimings> {
+        self.setup.clone()
+    }
+}
+
+#[tokio::test]
+async fn test_websocket_benchmark_clients() -> Result<()> {
+    let result = websocket_benchmark(&TransportConfig::default(), WebSocketClientMode::Local, 10, 64, 100).await?;
+    assert_eq!(result.total_bytes, 10 * 64 * 2);
+    assert_eq!(result.setup.map(|setup| setup.phases.len()), Some(2));
+
+    // Pipelined, with far more in flight than fits in the socket buffers and no progress output
+    let config = TransportConfig { window: 64, ..Default::default() };
+    let result = websocket_benchmark(&config, WebSocketClientMode::Local, 200, 64 * 1024, 0).await?;
+    assert_eq!(result.total_bytes, 200 * 64 * 1024 * 2);
+
+    // Remote mode waits for an external client, so grab a free port up front
+    let addr = std::net::TcpListener::bind("127.0.0.1:0")?.local_addr()?;
+    let config = TransportConfig { server_addr: addr, ..Default::default() };
+    let server = tokio::spawn(async move {
+        websocket_benchmark(&config, WebSocketClientMode::Remote, 10, 64, 100).await
+    });
+    let echoed = loop {
+        match websocket_echo_client(&format!("ws://{}", addr)).await {
+            Ok(echoed) => break echoed,
+            Err(_) => tokio::time::sleep(std::time::Duration::from_millis(10)).await,
+        }
+    };
+    assert_eq!(echoed, 10);
+    assert_eq!(server.await??.total_bytes, 10 * 64 * 2);
+
+    Ok(())
 }
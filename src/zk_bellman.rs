@@ -1,61 +1,337 @@
-use anyhow::Result;
+use anyhow::{anyhow, Result};
 use bellman::{
-    gadgets::boolean::{AllocatedBit, Boolean},
-    groth16, Circuit, ConstraintSystem, SynthesisError,
+    gadgets::{
+        boolean::{AllocatedBit, Boolean},
+        multipack,
+        sha256::sha256,
+    },
+    groth16, Circuit, ConstraintSystem, Index, LinearCombination, SynthesisError, Variable,
 };
-use bls12_381::Bls12;
+use bls12_381::{Bls12, Scalar};
 use ff::PrimeField;
 use rand::{thread_rng, Rng};
-use std::time::Instant;
+use sha2::{Digest, Sha256};
+use std::fmt;
+use std::time::{Duration, Instant};
 
+use crate::stats::{mean, LatencyHistogram, LatencyStats};
+use crate::zk_transport::{ProofSystem, ProveTimings};
+
+/// Proves knowledge of a payload whose SHA-256 digest is the public input.
+///
+/// Every payload bit is a private witness fed through the SHA-256 gadget, and
+/// the resulting digest is packed into as few public scalars as fit.
 #[derive(Clone)]
-struct MyCircuit {
-    payload: Vec<Boolean>,
-}
-
-impl<Scalar: PrimeField> Circuit<Scalar> for MyCircuit {
-    fn synthesize<CS: ConstraintSystem<Scalar>>(self, cs: &mut CS) -> Result<(), SynthesisError> {
-        let payload_bits = self.payload.into_iter().enumerate().map(|(i, b)| {
-            Ok(AllocatedBit::alloc(cs.namespace(|| format!("payload bit {}", i)), Some(b.get_value().unwrap()))?)
-        }).collect::<Result<Vec<_>, SynthesisError>>()?;
-
-        // Perform some arbitrary constraints on the payload bits
-        for (i, bit) in payload_bits.iter().enumerate() {
-            cs.enforce(
-                || format!("payload bit {} is boolean", i),
-                |lc| lc + bit.get_variable(),
-                |lc| lc + CS::one(),
-                |lc| lc + bit.get_variable(),
-            );
-        }
+struct PayloadCommitment {
+    len: usize,
+    /// `None` when only the shape of the circuit is needed, for parameter generation.
+    payload: Option<Vec<u8>>,
+}
 
-        Ok(())
+impl PayloadCommitment {
+    fn blank(len: usize) -> Self {
+        Self { len, payload: None }
+    }
+
+    fn from_payload(payload: &[u8]) -> Self {
+        Self { len: payload.len(), payload: Some(payload.to_vec()) }
     }
 }
 
-pub fn zk_bellman_benchmark(payload_size: usize, iterations: usize) -> Result<(std::time::Duration, usize)> {
+impl<S: PrimeField> Circuit<S> for PayloadCommitment {
+    fn synthesize<CS: ConstraintSystem<S>>(self, cs: &mut CS) -> Result<(), SynthesisError> {
+        // Bits in the order SHA-256 consumes them: bytes in turn, most significant bit first
+        let preimage = (0..self.len * 8)
+            .map(|i| {
+                let value = self.payload.as_ref().map(|payload| (payload[i / 8] >> (7 - i % 8)) & 1 == 1);
+                let bit = AllocatedBit::alloc(cs.namespace(|| format!("payload bit {}", i)), value)?;
+                Ok(Boolean::from(bit))
+            })
+            .collect::<Result<Vec<_>, SynthesisError>>()?;
+
+        let digest = sha256(cs.namespace(|| "sha256"), &preimage)?;
+        multipack::pack_into_inputs(cs.namespace(|| "digest"), &digest)
+    }
+}
+
+/// Collects the value of every variable a circuit allocates and ignores its
+/// constraints: the witness generation half of synthesis, on its own.
+struct WitnessAssignment<S> {
+    inputs: Vec<S>,
+    aux: Vec<S>,
+}
+
+impl<S: PrimeField> WitnessAssignment<S> {
+    fn new() -> Self {
+        Self { inputs: vec![S::ONE], aux: Vec::new() }
+    }
+}
+
+impl<S: PrimeField> ConstraintSystem<S> for WitnessAssignment<S> {
+    type Root = Self;
+
+    fn alloc<F, A, AR>(&mut self, _: A, f: F) -> Result<Variable, SynthesisError>
+    where
+        F: FnOnce() -> Result<S, SynthesisError>,
+        A: FnOnce() -> AR,
+        AR: Into<String>,
+    {
+        self.aux.push(f()?);
+        Ok(Variable::new_unchecked(Index::Aux(self.aux.len() - 1)))
+    }
+
+    fn alloc_input<F, A, AR>(&mut self, _: A, f: F) -> Result<Variable, SynthesisError>
+    where
+        F: FnOnce() -> Result<S, SynthesisError>,
+        A: FnOnce() -> AR,
+        AR: Into<String>,
+    {
+        self.inputs.push(f()?);
+        Ok(Variable::new_unchecked(Index::Input(self.inputs.len() - 1)))
+    }
+
+    fn enforce<A, AR, LA, LB, LC>(&mut self, _: A, _: LA, _: LB, _: LC)
+    where
+        A: FnOnce() -> AR,
+        AR: Into<String>,
+        LA: FnOnce(LinearCombination<S>) -> LinearCombination<S>,
+        LB: FnOnce(LinearCombination<S>) -> LinearCombination<S>,
+        LC: FnOnce(LinearCombination<S>) -> LinearCombination<S>,
+    {
+    }
+
+    fn push_namespace<NR, N>(&mut self, _: N)
+    where
+        NR: Into<String>,
+        N: FnOnce() -> NR,
+    {
+    }
+
+    fn pop_namespace(&mut self) {}
+
+    fn get_root(&mut self) -> &mut Self::Root {
+        self
+    }
+}
+
+// The public inputs a proof for `payload` is checked against
+fn public_inputs(payload: &[u8]) -> Vec<Scalar> {
+    multipack::compute_multipacking(&multipack::bytes_to_bits(&Sha256::digest(payload)))
+}
+
+/// Groth16 costs for one payload size, from [`zk_bellman_benchmark`].
+#[derive(Clone, Debug)]
+pub struct Groth16BenchResult {
+    pub proofs: usize,
+    /// Parameter generation and verifying key preparation, paid once.
+    pub setup: Duration,
+    /// One sample per proof created.
+    pub prove: LatencyStats,
+    /// One sample per proof checked on its own, including deserializing it and
+    /// hashing the payload, as a receiving peer would.
+    pub verify: LatencyStats,
+    /// Checking every proof at once with [`Groth16Prover::verify_batch`].
+    pub batch_verify: Duration,
+    /// Serialized size of a proof in bytes.
+    pub proof_size: usize,
+    /// Scalars the payload digest is packed into.
+    pub public_inputs: usize,
+    /// Payload bytes proved.
+    pub total_bytes: usize,
+}
+
+impl Groth16BenchResult {
+    /// Batch verification time spread over each proof in the batch.
+    pub fn batch_verify_per_proof(&self) -> Duration {
+        mean(self.batch_verify, self.proofs.max(1))
+    }
+}
+
+impl fmt::Display for Groth16BenchResult {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "Setup: {:.3} s, Prove: {}, Verify: {}, Batch verify: {:.3} ms/proof, Proof: {} bytes, {} public inputs",
+            self.setup.as_secs_f64(),
+            self.prove,
+            self.verify,
+            self.batch_verify_per_proof().as_secs_f64() * 1_000.0,
+            self.proof_size,
+            self.public_inputs
+        )
+    }
+}
+
+/// Proves `iterations` random payloads of `payload_size` bytes, then verifies the
+/// proofs one at a time and all together, timing each phase on its own.
+pub fn zk_bellman_benchmark(payload_size: usize, iterations: usize) -> Result<Groth16BenchResult> {
+    let start = Instant::now();
+    let prover = Groth16Prover::new(payload_size)?;
+    let setup = start.elapsed();
+
     let rng = &mut thread_rng();
-    let payload: Vec<u8> = (0..payload_size).map(|_| rng.gen()).collect();
-    let payload_bits = payload.iter().map(|&byte| Boolean::constant(byte > 127)).collect();
+    let payloads: Vec<Vec<u8>> = (0..iterations).map(|_| (0..payload_size).map(|_| rng.gen()).collect()).collect();
 
-    let circuit = MyCircuit { payload: payload_bits };
+    let mut prove = LatencyHistogram::with_capacity(iterations);
+    let mut proofs = Vec::with_capacity(iterations);
+    for payload in &payloads {
+        let start = Instant::now();
+        proofs.push(prover.prove(payload)?);
+        prove.record(start.elapsed());
+    }
 
-    let params = {
-        let mut rng = thread_rng();
-        groth16::generate_random_parameters::<Bls12, _, _>(circuit.clone(), &mut rng)?
-    };
+    let mut verify = LatencyHistogram::with_capacity(iterations);
+    for (payload, proof) in payloads.iter().zip(&proofs) {
+        let start = Instant::now();
+        prover.verify(payload, proof)?;
+        verify.record(start.elapsed());
+    }
 
+    let batch: Vec<_> = payloads.iter().zip(&proofs).map(|(payload, proof)| (&payload[..], &proof[..])).collect();
     let start = Instant::now();
+    prover.verify_batch(&batch)?;
+    let batch_verify = start.elapsed();
+
+    Ok(Groth16BenchResult {
+        proofs: iterations,
+        setup,
+        prove: prove.stats(),
+        verify: verify.stats(),
+        batch_verify,
+        proof_size: proofs.first().map_or(0, Vec::len),
+        public_inputs: prover.public_inputs(),
+        total_bytes: payload_size * iterations,
+    })
+}
+
+/// Groth16 prover for payloads of a fixed size, with parameters generated up front.
+///
+/// Each proof commits to the SHA-256 digest of its payload, so verifying it
+/// against any other payload fails.
+pub struct Groth16Prover {
+    payload_size: usize,
+    params: groth16::Parameters<Bls12>,
+    pvk: groth16::PreparedVerifyingKey<Bls12>,
+}
+
+impl Groth16Prover {
+    pub fn new(payload_size: usize) -> Result<Self> {
+        let circuit = PayloadCommitment::blank(payload_size);
+        let params = groth16::generate_random_parameters::<Bls12, _, _>(circuit, &mut thread_rng())?;
+        let pvk = groth16::prepare_verifying_key(&params.vk);
 
-    let mut rng = thread_rng();
-    for _ in 0..iterations {
-        let _proof = {
-            groth16::create_random_proof(circuit.clone(), &params, &mut rng)?
-        };
+        Ok(Self { payload_size, params, pvk })
     }
 
-    let elapsed = start.elapsed();
-    let total_bytes = payload_size * iterations;
+    /// Scalars each proof is checked against.
+    pub fn public_inputs(&self) -> usize {
+        self.params.vk.ic.len() - 1
+    }
+
+    /// Checks many `(payload, proof)` pairs at once, which costs far fewer
+    /// pairings than checking each on its own. Fails if any proof is invalid,
+    /// without saying which.
+    pub fn verify_batch(&self, items: &[(&[u8], &[u8])]) -> Result<()> {
+        let mut batch = groth16::batch::Verifier::new();
+        for (payload, proof) in items {
+            self.check_len(payload)?;
+            batch.queue((groth16::Proof::<Bls12>::read(*proof)?, public_inputs(payload)));
+        }
+        batch.verify(thread_rng(), &self.params.vk)?;
+        Ok(())
+    }
+
+    fn check_len(&self, payload: &[u8]) -> Result<()> {
+        if payload.len() != self.payload_size {
+            return Err(anyhow!("Expected a {} byte payload, got {}", self.payload_size, payload.len()));
+        }
+        Ok(())
+    }
+}
+
+impl ProofSystem for Groth16Prover {
+    fn prove(&self, payload: &[u8]) -> Result<Vec<u8>> {
+        self.prove_timed(payload).map(|(proof, _)| proof)
+    }
+
+    /// bellman generates the witness inside `create_random_proof`, so it counts
+    /// towards proving here and [`ProofSystem::time_witness`] times it on its own.
+    fn prove_timed(&self, payload: &[u8]) -> Result<(Vec<u8>, ProveTimings)> {
+        self.check_len(payload)?;
+        let circuit = PayloadCommitment::from_payload(payload);
+
+        let start = Instant::now();
+        let proof = groth16::create_random_proof(circuit, &self.params, &mut thread_rng())?;
+        let prove = start.elapsed();
+
+        let start = Instant::now();
+        let mut bytes = Vec::new();
+        proof.write(&mut bytes)?;
+        let serialize = start.elapsed();
+
+        Ok((bytes, ProveTimings { prove, serialize, ..Default::default() }))
+    }
+
+    fn time_witness(&self, payload: &[u8]) -> Result<Option<Duration>> {
+        self.check_len(payload)?;
+        let circuit = PayloadCommitment::from_payload(payload);
+
+        let start = Instant::now();
+        circuit.synthesize(&mut WitnessAssignment::<Scalar>::new())?;
+        Ok(Some(start.elapsed()))
+    }
+
+    fn verify(&self, payload: &[u8], proof: &[u8]) -> Result<()> {
+        self.check_len(payload)?;
+        let proof = groth16::Proof::<Bls12>::read(proof)?;
+        groth16::verify_proof(&self.pvk, &proof, &public_inputs(payload))?;
+        Ok(())
+    }
+}
+
+#[test]
+fn test_payload_commitment_circuit() -> Result<()> {
+    use bellman::gadgets::test::TestConstraintSystem;
+
+    let payload = [7; 32];
+    let mut cs = TestConstraintSystem::<Scalar>::new();
+    PayloadCommitment::from_payload(&payload).synthesize(&mut cs)?;
+    assert!(cs.is_satisfied());
+    assert!(cs.verify(&public_inputs(&payload)));
+
+    // The digest is bound to this exact payload
+    let mut tampered = payload;
+    tampered[31] ^= 1;
+    assert!(!cs.verify(&public_inputs(&tampered)));
+
+    Ok(())
+}
+
+#[test]
+fn test_groth16_prover() -> Result<()> {
+    let prover = Groth16Prover::new(1)?;
+    let proof = prover.prove(&[7])?;
+    prover.verify(&[7], &proof)?;
+
+    // A proof only verifies against the payload it was made for
+    assert!(prover.verify(&[6], &proof).is_err());
+    assert!(prover.verify(&[7, 7], &proof).is_err());
+
+    // A batch passes only if every proof in it does
+    let other = prover.prove(&[8])?;
+    prover.verify_batch(&[(&[7], &proof), (&[8], &other)])?;
+    assert!(prover.verify_batch(&[(&[7], &proof), (&[9], &other)]).is_err());
+
+    Ok(())
+}
+
+#[test]
+fn test_zk_bellman_benchmark() -> Result<()> {
+    let result = zk_bellman_benchmark(1, 2)?;
+    assert_eq!(result.proofs, 2);
+    assert_eq!(result.proof_size, 192);
+    assert_eq!(result.public_inputs, 2);
+    assert_eq!(result.total_bytes, 2);
 
-    Ok((elapsed, total_bytes))
+    Ok(())
 }
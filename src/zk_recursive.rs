@@ -1,126 +1,352 @@
 use anyhow::{anyhow, Result};
 use plonky2::field::goldilocks_field::GoldilocksField;
 use plonky2::field::types::Field;
+use plonky2::hash::poseidon::PoseidonHash;
+use plonky2::iop::generator::generate_partial_witness;
+use plonky2::gates::noop::NoopGate;
+use plonky2::iop::target::{BoolTarget, Target};
 use plonky2::iop::witness::{PartialWitness, WitnessWrite};
 use plonky2::plonk::circuit_builder::CircuitBuilder;
-use plonky2::plonk::circuit_data::CircuitConfig;
-use plonky2::plonk::config::PoseidonGoldilocksConfig;
-use plonky2::plonk::proof::{Proof, ProofWithPublicInputs};
+use plonky2::plonk::circuit_data::{CircuitConfig, CircuitData, CommonCircuitData, VerifierCircuitTarget};
+use plonky2::plonk::config::{Hasher, PoseidonGoldilocksConfig};
+use plonky2::plonk::proof::{ProofWithPublicInputs, ProofWithPublicInputsTarget};
+use plonky2::plonk::prover::prove_with_partition_witness;
+use plonky2::recursion::cyclic_recursion::check_cyclic_proof_verifier_data;
+use plonky2::recursion::dummy_circuit::cyclic_base_proof;
+use plonky2::util::timing::TimingTree;
+use std::time::Instant;
+
+use crate::zk_transport::{ProofSystem, ProveTimings};
 
 type F = GoldilocksField; // Using GoldilocksField for simplicity
 
+type C = PoseidonGoldilocksConfig;
+
+/// A point, reached from a genesis point in some number of moves.
+///
+/// Every move's proof recursively verifies the proof of the move before it, so
+/// the latest proof alone attests to the whole path from genesis.
 #[derive(Clone)]
-struct State {
-    proof: Option<Vec<u8>>, // Proof for ZK verification
+pub struct State {
+    proof: Option<Vec<u8>>, // Proof of every move since genesis, if there were any
+    genesis: [F; 2],
+    steps: u64,
     x: F,
     y: F,
 }
 
 impl State {
-    pub fn new(proof: Option<Vec<u8>>, x: u32, y: u32) -> Self {
-        Self {
-            proof,
-            x: F::from_canonical_u32(x),
-            y: F::from_canonical_u32(y),
-        }
+    /// A genesis state at `(x, y)`, with no moves to prove yet.
+    pub fn new(x: u32, y: u32) -> Self {
+        let (x, y) = (F::from_canonical_u32(x), F::from_canonical_u32(y));
+        Self { proof: None, genesis: [x, y], steps: 0, x, y }
+    }
+
+    /// Moves made since the genesis state.
+    pub fn steps(&self) -> u64 {
+        self.steps
+    }
+
+    /// Moves by `(x_prime, y_prime)`, building the circuit from scratch. Use a
+    /// [`StateMachine`] to prove more than one transition.
+    pub fn move_by(&self, x_prime: u32, y_prime: u32) -> Result<Self> {
+        StateMachine::new()?.move_by(self, x_prime, y_prime)
+    }
+
+    /// Checks the proof of the path to this state, building the circuit from scratch.
+    pub fn verify(&self) -> Result<bool> {
+        StateMachine::new()?.verify(self)
     }
 
-    fn build_circuit() -> (CircuitBuilder<F, 2>, [plonky2::iop::target::Target; 6]) {
+    // Public inputs of a proof for this state, ahead of the circuit's own verifier data
+    fn public_inputs(&self) -> [F; 5] {
+        let [genesis_x, genesis_y] = self.genesis;
+        [genesis_x, genesis_y, self.x, self.y, F::from_canonical_u64(self.steps)]
+    }
+}
+
+/// The compiled state transition circuit, built once and shared by every
+/// transition it proves or verifies.
+///
+/// Each transition proves one move and, unless it starts from genesis, verifies
+/// the proof of the previous transition in-circuit (cyclic recursion), so
+/// proofs don't grow with the length of the path.
+pub struct StateMachine {
+    data: CircuitData<F, C, 2>,
+    targets: TransitionTargets,
+}
+
+struct TransitionTargets {
+    genesis: [Target; 2],
+    moves: [Target; 2],
+    /// Set when there is a previous transition to verify, clear from genesis.
+    has_previous: BoolTarget,
+    previous: ProofWithPublicInputsTarget<2>,
+    verifier_data: VerifierCircuitTarget,
+}
+
+impl StateMachine {
+    pub fn new() -> Result<Self> {
         let config = CircuitConfig::standard_recursion_config();
         let mut builder = CircuitBuilder::<F, 2>::new(config);
+        let one = builder.one();
 
-        // Create targets for coordinates
-        let x_target = builder.add_virtual_target();
-        let y_target = builder.add_virtual_target();
-        let x_prime_target = builder.add_virtual_target();
-        let y_prime_target = builder.add_virtual_target();
-        let new_x_target = builder.add_virtual_target();
-        let new_y_target = builder.add_virtual_target();
-
-        // Add constraints
-        let computed_new_x = builder.add(x_target, x_prime_target);
-        let computed_new_y = builder.add(y_target, y_prime_target);
-        builder.connect(computed_new_x, new_x_target);
-        builder.connect(computed_new_y, new_y_target);
-
-        // Register public inputs
-        builder.register_public_input(x_target);
-        builder.register_public_input(y_target);
-        builder.register_public_input(x_prime_target);
-        builder.register_public_input(y_prime_target);
-        builder.register_public_input(new_x_target);
-        builder.register_public_input(new_y_target);
-
-        let targets = [
-            x_target, y_target, 
-            x_prime_target, y_prime_target,
-            new_x_target, new_y_target
-        ];
-
-        (builder, targets)
-    }
+        // Public inputs: genesis point, current point and number of moves, in
+        // the order of `State::public_inputs`
+        let genesis = builder.add_virtual_target_arr::<2>();
+        builder.register_public_inputs(&genesis);
+        let current = builder.add_virtual_target_arr::<2>();
+        builder.register_public_inputs(&current);
+        let steps = builder.add_virtual_public_input();
 
-    pub fn move_by(&self, x_prime: u32, y_prime: u32) -> Result<Self> {
-        let new_x = self.x + F::from_canonical_u32(x_prime);
-        let new_y = self.y + F::from_canonical_u32(y_prime);
+        let mut common_data = common_data_for_recursion();
+        let verifier_data = builder.add_verifier_data_public_inputs();
+        common_data.num_public_inputs = builder.num_public_inputs();
 
-        let (builder, targets) = Self::build_circuit();
-        let data = builder.build::<PoseidonGoldilocksConfig>();
+        let has_previous = builder.add_virtual_bool_target_safe();
+        let previous = builder.add_virtual_proof_with_pis(&common_data);
+        let previous_inputs = previous.public_inputs.clone();
+
+        // The whole path shares one genesis; from genesis there is no previous
+        // proof, so the dummy in its place carries the same genesis
+        builder.connect(genesis[0], previous_inputs[0]);
+        builder.connect(genesis[1], previous_inputs[1]);
+
+        // Move on from where the previous transition ended, or from genesis
+        let moves = builder.add_virtual_target_arr::<2>();
+        for i in 0..2 {
+            let from = builder.select(has_previous, previous_inputs[2 + i], genesis[i]);
+            let to = builder.add(from, moves[i]);
+            builder.connect(to, current[i]);
+        }
+        let new_steps = builder.mul_add(has_previous.target, previous_inputs[4], one);
+        builder.connect(new_steps, steps);
+
+        builder.conditionally_verify_cyclic_proof_or_dummy::<C>(has_previous, &previous, &common_data)?;
+        let data = builder.build::<C>();
+
+        Ok(Self { data, targets: TransitionTargets { genesis, moves, has_previous, previous, verifier_data } })
+    }
+
+    /// Proves the move from `state` by `(x_prime, y_prime)`, verifying the
+    /// proof `state` carries along the way.
+    pub fn move_by(&self, state: &State, x_prime: u32, y_prime: u32) -> Result<State> {
+        let (x_prime, y_prime) = (F::from_canonical_u32(x_prime), F::from_canonical_u32(y_prime));
 
         // Create witness
+        let targets = &self.targets;
         let mut pw = PartialWitness::new();
-        pw.set_target(targets[0], self.x)?;
-        pw.set_target(targets[1], self.y)?;
-        pw.set_target(targets[2], F::from_canonical_u32(x_prime))?;
-        pw.set_target(targets[3], F::from_canonical_u32(y_prime))?;
-        pw.set_target(targets[4], new_x)?;
-        pw.set_target(targets[5], new_y)?;
+        pw.set_target_arr(&targets.genesis, &state.genesis)?;
+        pw.set_target_arr(&targets.moves, &[x_prime, y_prime])?;
+        pw.set_verifier_data_target(&targets.verifier_data, &self.data.verifier_only)?;
+        match &state.proof {
+            Some(proof) => {
+                pw.set_bool_target(targets.has_previous, true)?;
+                pw.set_proof_with_pis_target(&targets.previous, &self.read_proof(proof)?)?;
+            }
+            None => {
+                let genesis = state.genesis.into_iter().enumerate().collect();
+                let base = cyclic_base_proof(&self.data.common, &self.data.verifier_only, genesis);
+                pw.set_bool_target(targets.has_previous, false)?;
+                pw.set_proof_with_pis_target(&targets.previous, &base)?;
+            }
+        }
 
-        let proof = data.prove(pw)?;
+        let proof = self.data.prove(pw)?;
 
-        Ok(Self {
+        Ok(State {
             proof: Some(proof.to_bytes()),
-            x: new_x,
-            y: new_y,
+            genesis: state.genesis,
+            steps: state.steps + 1,
+            x: state.x + x_prime,
+            y: state.y + y_prime,
         })
     }
 
-    fn verify(&self) -> Result<bool> {
-        match &self.proof {
+    /// Checks that the proof `state` carries attests to a path from its
+    /// genesis to its point in its number of moves.
+    pub fn verify(&self, state: &State) -> Result<bool> {
+        match &state.proof {
             Some(proof_bytes) => {
-                let (builder, _) = Self::build_circuit();
-                let data = builder.build::<PoseidonGoldilocksConfig>();
-
-                // Deserialize and verify the proof
-                let proof = ProofWithPublicInputs::<F, PoseidonGoldilocksConfig, 2>::from_bytes(
-                    proof_bytes.clone(),
-                    &data.common,
-                )?;
-                
-                data.verify(proof)?;
+                let proof = self.read_proof(proof_bytes)?;
+                if proof.public_inputs[..5] != state.public_inputs() {
+                    return Err(anyhow!("Proof does not attest to this state"));
+                }
+                // The verifier data in the public inputs must be this circuit's own,
+                // or every recursive step could have checked a different circuit
+                check_cyclic_proof_verifier_data(&proof, &self.data.verifier_only, &self.data.common)?;
+
+                self.data.verify(proof)?;
                 Ok(true)
             }
             None => Err(anyhow!("No proof to verify")),
         }
     }
+
+    fn read_proof(&self, bytes: &[u8]) -> Result<ProofWithPublicInputs<F, C, 2>> {
+        ProofWithPublicInputs::from_bytes(bytes.to_vec(), &self.data.common)
+    }
+}
+
+// Shape of a circuit that verifies a proof of itself: a recursive verifier of a
+// recursive verifier, padded to 2^12 gates so the sizes converge
+fn common_data_for_recursion() -> CommonCircuitData<F, 2> {
+    let config = CircuitConfig::standard_recursion_config();
+    let data = CircuitBuilder::<F, 2>::new(config.clone()).build::<C>();
+
+    let mut builder = CircuitBuilder::<F, 2>::new(config.clone());
+    let proof = builder.add_virtual_proof_with_pis(&data.common);
+    let verifier_data = builder.add_virtual_verifier_data(data.common.config.fri_config.cap_height);
+    builder.verify_proof::<C>(&proof, &verifier_data, &data.common);
+    let data = builder.build::<C>();
+
+    let mut builder = CircuitBuilder::<F, 2>::new(config);
+    let proof = builder.add_virtual_proof_with_pis(&data.common);
+    let verifier_data = builder.add_virtual_verifier_data(data.common.config.fri_config.cap_height);
+    builder.verify_proof::<C>(&proof, &verifier_data, &data.common);
+    while builder.num_gates() < 1 << 12 {
+        builder.add_gate(NoopGate, vec![]);
+    }
+    builder.build::<C>().common
+}
+
+/// Plonky2 prover attesting to the Poseidon hash of a fixed-size payload.
+///
+/// The payload is packed into 32-bit limbs, range-checked in-circuit and hashed;
+/// the hash is the proof's public input.
+pub struct Plonky2Prover {
+    payload_size: usize,
+    data: CircuitData<F, PoseidonGoldilocksConfig, 2>,
+    payload_targets: Vec<Target>,
+}
+
+impl Plonky2Prover {
+    pub fn new(payload_size: usize) -> Result<Self> {
+        let config = CircuitConfig::standard_recursion_config();
+        let mut builder = CircuitBuilder::<F, 2>::new(config);
+
+        let payload_targets = builder.add_virtual_targets(payload_size.div_ceil(4));
+        for &target in &payload_targets {
+            builder.range_check(target, 32);
+        }
+        let hash = builder.hash_n_to_hash_no_pad::<PoseidonHash>(payload_targets.clone());
+        builder.register_public_inputs(&hash.elements);
+
+        let data = builder.build::<PoseidonGoldilocksConfig>();
+
+        Ok(Self { payload_size, data, payload_targets })
+    }
+
+    fn check_len(&self, payload: &[u8]) -> Result<()> {
+        if payload.len() != self.payload_size {
+            return Err(anyhow!("Expected a {} byte payload, got {}", self.payload_size, payload.len()));
+        }
+        Ok(())
+    }
+
+    fn payload_elements(payload: &[u8]) -> Vec<F> {
+        payload
+            .chunks(4)
+            .map(|chunk| {
+                let mut limb = [0u8; 4];
+                limb[..chunk.len()].copy_from_slice(chunk);
+                F::from_canonical_u32(u32::from_le_bytes(limb))
+            })
+            .collect()
+    }
+}
+
+impl ProofSystem for Plonky2Prover {
+    fn prove(&self, payload: &[u8]) -> Result<Vec<u8>> {
+        Ok(self.prove_timed(payload)?.0)
+    }
+
+    fn prove_timed(&self, payload: &[u8]) -> Result<(Vec<u8>, ProveTimings)> {
+        self.check_len(payload)?;
+
+        // Set the payload limbs, then run the generators that fill in every other wire
+        let start = Instant::now();
+        let mut pw = PartialWitness::new();
+        for (&target, value) in self.payload_targets.iter().zip(Self::payload_elements(payload)) {
+            pw.set_target(target, value)?;
+        }
+        let partition_witness = generate_partial_witness(pw, &self.data.prover_only, &self.data.common)?;
+        let witness = start.elapsed();
+
+        let start = Instant::now();
+        let proof = prove_with_partition_witness(
+            &self.data.prover_only,
+            &self.data.common,
+            partition_witness,
+            &mut TimingTree::default(),
+        )?;
+        let prove = start.elapsed();
+
+        let start = Instant::now();
+        let bytes = proof.to_bytes();
+        let serialize = start.elapsed();
+
+        Ok((bytes, ProveTimings { witness, prove, serialize }))
+    }
+
+    fn verify(&self, payload: &[u8], proof: &[u8]) -> Result<()> {
+        // Zero padding in the last limb would otherwise hide trailing zero bytes
+        self.check_len(payload)?;
+        let proof = ProofWithPublicInputs::<F, PoseidonGoldilocksConfig, 2>::from_bytes(
+            proof.to_vec(),
+            &self.data.common,
+        )?;
+
+        let expected = PoseidonHash::hash_no_pad(&Self::payload_elements(payload));
+        if proof.public_inputs != expected.elements {
+            return Err(anyhow!("Proof does not commit to the received payload"));
+        }
+
+        self.data.verify(proof)
+    }
 }
 
 #[test]
 fn test_state_transitions() -> Result<()> {
-    let mut states = vec![State::new(None, 0, 0)];
+    let machine = StateMachine::new()?;
+    let mut states = vec![State::new(0, 0)];
     
-    // Create 3 state transitions
+    // Create 30 state transitions, each proof verifying the one before
     for i in 0..30 {
-        let next_state = states[i].move_by(1, 1)?;
+        let next_state = machine.move_by(&states[i], 1, 1)?;
         states.push(next_state);
-        if (i > 0) {
+        if i > 0 {
             println!("s({}) -> s({}): ({}, {}) -> ({}, {})", i-1, i, states[i-1].x, states[i-1].y, states[i].x, states[i].y);
         }
     }
 
-    // Verify final state
-    assert!(states.last().unwrap().verify()?);
+    // The final proof alone attests to all 30 moves from (0, 0), also against a
+    // circuit built from scratch
+    let last = states.last().unwrap();
+    assert_eq!(last.steps(), 30);
+    assert!(machine.verify(last)?);
+    assert!(last.verify()?);
+
+    // It doesn't pass for a different end point or path length
+    let mut forged = last.clone();
+    forged.x += F::ONE;
+    assert!(machine.verify(&forged).is_err());
+    let mut forged = last.clone();
+    forged.steps = 29;
+    assert!(machine.verify(&forged).is_err());
+
+    Ok(())
+}
+
+#[test]
+fn test_plonky2_prover() -> Result<()> {
+    let prover = Plonky2Prover::new(3)?;
+    let proof = prover.prove(&[1, 2, 3])?;
+    prover.verify(&[1, 2, 3], &proof)?;
 
+    // Same limbs once padded, but not the payload the proof was made for
+    assert!(prover.verify(&[1, 2, 3, 0], &proof).is_err());
+    assert!(prover.verify(&[1, 2, 4], &proof).is_err());
 
     Ok(())
 }
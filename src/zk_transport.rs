@@ -0,0 +1,287 @@
+use anyhow::{anyhow, Result};
+use rand::{thread_rng, RngCore};
+use std::str::FromStr;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use tokio::sync::oneshot;
+
+use crate::stats::{mean, BenchResult, LatencyHistogram, ProofPhases};
+use crate::transport::{connect_timed, Transport, TransportConfig};
+use crate::zk_bellman::Groth16Prover;
+use crate::zk_recursive::Plonky2Prover;
+
+/// Frames are split into chunks no larger than this, so proofs bigger than a
+/// UDP datagram or an SCTP message can still be carried.
+const CHUNK_SIZE: usize = 60_000;
+
+/// A proof system that attests to a message payload.
+///
+/// Implementations are built for a fixed payload size, so any setup cost is paid
+/// once up front rather than per message.
+pub trait ProofSystem: Send + Sync {
+    /// Produce a serialized proof for `payload`.
+    fn prove(&self, payload: &[u8]) -> Result<Vec<u8>>;
+
+    /// Like [`ProofSystem::prove`], also reporting how long each stage took.
+    /// Systems that can't tell the stages apart count it all as proving.
+    fn prove_timed(&self, payload: &[u8]) -> Result<(Vec<u8>, ProveTimings)> {
+        let start = Instant::now();
+        let proof = self.prove(payload)?;
+        Ok((proof, ProveTimings { prove: start.elapsed(), ..Default::default() }))
+    }
+
+    /// How long generating the witness for `payload` takes, measured in a pass
+    /// of its own. Only systems whose [`ProofSystem::prove_timed`] can't split
+    /// it out of proving report this.
+    fn time_witness(&self, _payload: &[u8]) -> Result<Option<Duration>> {
+        Ok(None)
+    }
+
+    /// Check a serialized proof produced by [`ProofSystem::prove`] against `payload`.
+    fn verify(&self, payload: &[u8], proof: &[u8]) -> Result<()>;
+}
+
+/// Time [`ProofSystem::prove_timed`] spent on each stage of a single proof.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct ProveTimings {
+    pub witness: Duration,
+    pub prove: Duration,
+    pub serialize: Duration,
+}
+
+/// The provers that can be attached to transport traffic.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ZkBackend {
+    /// Groth16 over BLS12-381, from [`crate::zk_bellman`].
+    Groth16,
+    /// Plonky2 over Goldilocks, from [`crate::zk_recursive`].
+    Plonky2,
+}
+
+impl ZkBackend {
+    pub fn name(&self) -> &'static str {
+        match self {
+            ZkBackend::Groth16 => "Groth16",
+            ZkBackend::Plonky2 => "Plonky2",
+        }
+    }
+
+    /// Set up a prover for payloads of `msg_size` bytes.
+    pub fn prover(&self, msg_size: usize) -> Result<Arc<dyn ProofSystem>> {
+        Ok(match self {
+            ZkBackend::Groth16 => Arc::new(Groth16Prover::new(msg_size)?),
+            ZkBackend::Plonky2 => Arc::new(Plonky2Prover::new(msg_size)?),
+        })
+    }
+}
+
+impl FromStr for ZkBackend {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s.to_ascii_lowercase().as_str() {
+            "groth16" => Ok(ZkBackend::Groth16),
+            "plonky2" => Ok(ZkBackend::Plonky2),
+            other => Err(anyhow!("Unknown ZK backend: {}", other)),
+        }
+    }
+}
+
+/// Like [`crate::transport::ping_pong`], but every message carries a proof of its
+/// payload which the server verifies before echoing the message back.
+///
+/// Proving and verification happen inside the timed loop, so they are part of
+/// every recorded round trip. The returned byte count only includes payload
+/// bytes, so throughput is directly comparable with the plain runs.
+pub async fn zk_ping_pong<T: Transport>(
+    config: &TransportConfig,
+    iterations: u64,
+    msg_size: usize,
+    prover: Arc<dyn ProofSystem>,
+) -> Result<BenchResult> {
+    let (mut client, mut server, setup) = connect_timed::<T>(config).await?;
+    let server_addr = server.local_addr();
+
+    let verifier = prover.clone();
+    let (stop, mut stopped) = oneshot::channel();
+    let echo = tokio::spawn(async move {
+        for _ in 0..iterations {
+            let frame = tokio::select! {
+                _ = &mut stopped => break,
+                frame = recv_frame(&mut server) => frame?,
+            };
+            let verifier = verifier.clone();
+            let frame = tokio::task::spawn_blocking(move || {
+                let (proof, payload) = decode_frame(&frame)?;
+                verifier.verify(payload, proof)?;
+                Result::<_>::Ok(frame)
+            })
+            .await??;
+            send_frame(&mut server, &frame).await?;
+        }
+        Result::<T>::Ok(server)
+    });
+
+    let run = async {
+        let mut total_bytes = 0;
+        let mut histogram = LatencyHistogram::with_capacity(iterations as usize);
+
+        let start = Instant::now();
+        for _ in 0..iterations {
+            let sent = Instant::now();
+            let mut payload = vec![0; msg_size];
+            thread_rng().fill_bytes(&mut payload);
+
+            let prover = prover.clone();
+            let frame = tokio::task::spawn_blocking(move || {
+                let proof = prover.prove(&payload)?;
+                Result::<_>::Ok(encode_frame(&proof, &payload))
+            })
+            .await??;
+
+            send_frame(&mut client, &frame).await?;
+            let reply = recv_frame(&mut client).await?;
+            let (_, payload) = decode_frame(&reply)?;
+            histogram.record(sent.elapsed());
+            total_bytes += msg_size + payload.len();
+        }
+        Result::<_>::Ok((start.elapsed(), total_bytes, histogram))
+    }
+    .await;
+
+    // Stop echoing and close both ends even if the run failed, then report the
+    // first thing that went wrong
+    let _ = stop.send(());
+    let server = echo.await.map_err(anyhow::Error::from).and_then(|server| server);
+    let client_closed = client.close().await;
+    let server_closed = match server {
+        Ok(server) => server.close().await,
+        Err(e) => Err(e),
+    };
+    let (elapsed, total_bytes, histogram) = run?;
+    client_closed?;
+    server_closed?;
+
+    let result = BenchResult::new(elapsed, total_bytes, &histogram).with_setup(setup);
+    Ok(match server_addr {
+        Some(addr) => result.with_server_addr(addr),
+        None => result,
+    })
+}
+
+/// Sets `backend` up for `msg_size` byte payloads, then proves and verifies
+/// `iterations` random payloads locally, without any transport. Each prove +
+/// verify cycle is recorded, and the result breaks the time down by phase.
+pub fn proof_benchmark(backend: ZkBackend, iterations: u64, msg_size: usize) -> Result<BenchResult> {
+    let start = Instant::now();
+    let prover = backend.prover(msg_size)?;
+    let mut phases = ProofPhases { setup: start.elapsed(), ..Default::default() };
+
+    let mut histogram = LatencyHistogram::with_capacity(iterations as usize);
+    let mut payload = vec![0; msg_size];
+
+    let start = Instant::now();
+    for _ in 0..iterations {
+        thread_rng().fill_bytes(&mut payload);
+
+        let proved = Instant::now();
+        let (proof, timings) = prover.prove_timed(&payload)?;
+        let verified = Instant::now();
+        prover.verify(&payload, &proof)?;
+        phases.verify += verified.elapsed();
+        histogram.record(proved.elapsed());
+
+        phases.witness += timings.witness;
+        phases.prove += timings.prove;
+        phases.serialize += timings.serialize;
+        phases.proof_size = proof.len();
+    }
+    let elapsed = start.elapsed();
+
+    // Witness generation that proving does internally is timed separately, so
+    // it doesn't add an extra pass to every measured cycle
+    for _ in 0..iterations {
+        thread_rng().fill_bytes(&mut payload);
+        match prover.time_witness(&payload)? {
+            Some(witness) => phases.witness += witness,
+            None => break,
+        }
+    }
+
+    if iterations > 0 {
+        let per_proof = |total| mean(total, iterations as usize);
+        phases.witness = per_proof(phases.witness);
+        phases.prove = per_proof(phases.prove);
+        phases.serialize = per_proof(phases.serialize);
+        phases.verify = per_proof(phases.verify);
+    }
+
+    Ok(BenchResult::new(elapsed, msg_size * iterations as usize, &histogram).with_phases(phases))
+}
+
+// Lays a frame out as [proof length][proof][payload]
+fn encode_frame(proof: &[u8], payload: &[u8]) -> Vec<u8> {
+    let mut frame = Vec::with_capacity(4 + proof.len() + payload.len());
+    frame.extend_from_slice(&(proof.len() as u32).to_be_bytes());
+    frame.extend_from_slice(proof);
+    frame.extend_from_slice(payload);
+    frame
+}
+
+// Splits a frame back into its proof and payload
+fn decode_frame(frame: &[u8]) -> Result<(&[u8], &[u8])> {
+    if frame.len() < 4 {
+        return Err(anyhow!("Frame of {} bytes is too short", frame.len()));
+    }
+    let proof_len = u32::from_be_bytes(frame[..4].try_into()?) as usize;
+    let body = &frame[4..];
+    if body.len() < proof_len {
+        return Err(anyhow!("Frame truncated: proof of {} bytes, {} available", proof_len, body.len()));
+    }
+    Ok(body.split_at(proof_len))
+}
+
+// Sends a frame as one or more chunks, the first prefixed with the total length
+async fn send_frame<T: Transport>(transport: &mut T, frame: &[u8]) -> Result<()> {
+    let mut data = Vec::with_capacity(4 + frame.len());
+    data.extend_from_slice(&(frame.len() as u32).to_be_bytes());
+    data.extend_from_slice(frame);
+    for chunk in data.chunks(CHUNK_SIZE) {
+        transport.send(chunk).await?;
+    }
+    Ok(())
+}
+
+// Reassembles a frame written by `send_frame`
+async fn recv_frame<T: Transport>(transport: &mut T) -> Result<Vec<u8>> {
+    let first = transport.recv().await?;
+    if first.len() < 4 {
+        return Err(anyhow!("Chunk of {} bytes is too short", first.len()));
+    }
+    let len = u32::from_be_bytes(first[..4].try_into()?) as usize;
+    let mut frame = first[4..].to_vec();
+    while frame.len() < len {
+        frame.extend_from_slice(&transport.recv().await?);
+    }
+    if frame.len() != len {
+        return Err(anyhow!("Frame overran: expected {} bytes, got {}", len, frame.len()));
+    }
+    Ok(frame)
+}
+
+#[tokio::test]
+async fn test_zk_ping_pong() -> Result<()> {
+    use crate::UdpTransport;
+
+    for backend in [ZkBackend::Groth16, ZkBackend::Plonky2] {
+        let prover = backend.prover(64)?;
+        let result = zk_ping_pong::<UdpTransport>(&TransportConfig::default(), 2, 64, prover).await?;
+        assert_eq!(result.total_bytes, 2 * 64 * 2);
+    }
+
+    // A run that fails part way still returns, rather than leaving the echo end running
+    let prover = ZkBackend::Plonky2.prover(64)?;
+    assert!(zk_ping_pong::<UdpTransport>(&TransportConfig::default(), 2, 32, prover).await.is_err());
+
+    Ok(())
+}